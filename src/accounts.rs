@@ -1,7 +1,8 @@
-use anyhow::Error;
+use crate::error::Error;
 use serde::{Deserialize, Serialize};
 
-use crate::{http, MAIL_API_URL};
+use crate::datetime::DateTime;
+use crate::http;
 use crate::http::Client;
 use crate::user::User;
 
@@ -14,11 +15,10 @@ pub struct Account {
     pub used: i64,
     #[serde(rename = "isDisabled")]
     pub is_disabled: bool,
-    #[serde(rename = "createdAt")]
-    pub created_at: serde_json::Value,
-    #[serde(rename = "updatedAt")]
-    pub updated_at: ::serde_json::Value,
-    //TODO these are not values, they're utc date
+    #[serde(rename = "createdAt", default, with = "crate::datetime::option")]
+    pub created_at: Option<DateTime>,
+    #[serde(rename = "updatedAt", default, with = "crate::datetime::option")]
+    pub updated_at: Option<DateTime>,
     #[serde(rename = "@context")]
     pub context: Option<String>,
     #[serde(rename = "@id")]
@@ -47,15 +47,14 @@ impl Account {
     }
 }
 
-pub(crate) async fn create(user: &User) -> Result<Account, Error> {
-    let client = Client::new()?.build()?;
-
+pub async fn create(client: &Client, user: &User) -> Result<Account, Error> {
     log::debug!("Creating account for user {:?}", user);
 
     let json = serde_json::json!(Account::from_user(user));
     let json_str = json.to_string();
     let response = client
-        .post(format!("{}/accounts", MAIL_API_URL).as_str())
+        .post("/accounts")
+        .retry(client.retry_policy().retry_account_creation)
         .body(json_str)
         .send()
         .await?;
@@ -72,13 +71,12 @@ pub(crate) async fn create(user: &User) -> Result<Account, Error> {
     Ok(serde_json::from_str(&response)?)
 }
 
-pub(crate) async fn get(token: &str, id: &str) -> Result<Account, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
+pub async fn get(client: &Client, token: &str, id: &str) -> Result<Account, Error> {
     log::debug!("Searching for account with id {}", id);
 
     let response = client
-        .get(&format!("{}/accounts/{}", MAIL_API_URL, id))
+        .get(&format!("/accounts/{}", id))
+        .bearer_auth(token)
         .send()
         .await?;
 
@@ -94,14 +92,13 @@ pub(crate) async fn get(token: &str, id: &str) -> Result<Account, Error> {
     Ok(serde_json::from_str(&response)?)
 }
 
-pub(crate) async fn delete(token: &str, id: &str) -> Result<(), Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
+pub async fn delete(client: &Client, token: &str, id: &str) -> Result<(), Error> {
     log::debug!("Searching for account with id {}", id);
 
 
     let response = client
-        .delete(&format!("{}/accounts/{}", MAIL_API_URL, id))
+        .delete(&format!("/accounts/{}", id))
+        .bearer_auth(token)
         .send()
         .await?;
 
@@ -113,13 +110,12 @@ pub(crate) async fn delete(token: &str, id: &str) -> Result<(), Error> {
     Ok(())
 }
 
-pub(crate) async fn me(token: &str) -> Result<Account, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
+pub async fn me(client: &Client, token: &str) -> Result<Account, Error> {
     log::debug!("Getting me");
 
     let builder = client
-        .get(&format!("{}/me", MAIL_API_URL));
+        .get("/me")
+        .bearer_auth(token);
 
     let response = builder
         .send()
@@ -137,6 +133,15 @@ pub(crate) async fn me(token: &str) -> Result<Account, Error> {
     Ok(serde_json::from_str(&response)?)
 }
 
+#[cfg(test)]
+pub(crate) fn account(id: &str) -> Account {
+    Account {
+        address: format!("{}@example.test", id),
+        id: Some(id.to_string()),
+        ..Default::default()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -145,15 +150,14 @@ mod tests {
     #[tokio::test]
     async fn test_accounts_create() -> Result<(), Error> {
         pretty_env_logger::try_init().ok();
+        let client = Client::new()?;
 
-        let user = User::default().with_domain(&crate::domains::domains().await?.any().domain);
-        assert_eq!(
-            create(&user)
+        let user = User::default().with_domain(&crate::domains::domains(&client).await?.any().unwrap().domain);
+        assert!(
+            !create(&client, &user)
                 .await?
                 .address
-                .as_str()
-                .is_empty(),
-            false
+                .is_empty()
         );
         Ok(())
     }
@@ -161,32 +165,31 @@ mod tests {
     #[tokio::test]
     async fn test_accounts() -> Result<(), Error> {
         pretty_env_logger::try_init().ok();
-        let user = User::default().with_domain(&crate::domains::domains().await?.any().domain);
+        let client = Client::new()?;
+        let user = User::default().with_domain(&crate::domains::domains(&client).await?.any().unwrap().domain);
 
-        let create = create(&user).await.unwrap();
+        let create = create(&client, &user).await.unwrap();
 
         let token = token(&user).await.unwrap();
 
 
-        assert_eq!(
-            create
+        assert!(
+            !create
                 .address
-                .as_str()
-                .is_empty(),
-            false
+                .is_empty()
         );
 
         let id = create.id.unwrap();
 
-        let get = get(&token.token, &id).await?;
+        let get = get(&client, &token.token, &id).await?;
 
         assert_eq!(get.id.unwrap(), id.clone());
 
-        let me = me(&token.token).await?;
+        let me = me(&client, &token.token).await?;
 
         assert_eq!(me.id.unwrap(), id.clone());
 
-        delete(&token.token, &id).await.unwrap();
+        delete(&client, &token.token, &id).await.unwrap();
 
         Ok(())
     }
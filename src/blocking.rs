@@ -0,0 +1,479 @@
+//! A synchronous API for programs that don't run an async runtime
+//!
+//! Mirrors the functions at the crate root, [`crate::MailTmClient`] and [`crate::Inbox`], sharing
+//! the same types.
+//! Every client drives the async one on its own single threaded tokio runtime, so these must not
+//! be called from within an async context, they'd panic the same way nested runtimes do.
+//!
+//! # Example
+//! ```
+//! use mail_tm_rs::blocking;
+//! use mail_tm_rs::user::User;
+//!
+//! fn main() -> Result<(), mail_tm_rs::Error> {
+//!     let client = blocking::MailTmClient::new()?;
+//!     //let inbox = client.create_inbox()?;
+//!     //let user = User::default().with_domain(&client.domains()?.any().ok_or(mail_tm_rs::Error::NoDomain)?.domain);
+//!     //let account = client.create_account(&user)?;
+//!     //let user = client.update_token(&user)?;
+//!     //let messages = client.list_messages(&user, None)?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_util::stream::{Stream, StreamExt};
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::Duration;
+
+use crate::accounts::Account;
+use crate::client::MailTmClientBuilder;
+use crate::domains::Domain;
+use crate::error::Error;
+use crate::events::InboxEvent;
+use crate::hydra::HydraCollection;
+use crate::messages::{Attachment, Message, MessagePatch, MessageSummary};
+use crate::sources::Source;
+use crate::token::Token;
+use crate::user::User;
+use crate::wait::MessageFilter;
+
+/// A blocking [`crate::MailTmClient`], cloning is cheap and shares the runtime
+#[derive(Debug, Clone)]
+pub struct MailTmClient {
+    inner: crate::MailTmClient,
+    runtime: Arc<Runtime>,
+}
+
+impl MailTmClient {
+    /// Creates a client for `https://api.mail.tm` with the default settings
+    pub fn new() -> Result<MailTmClient, Error> {
+        MailTmClient::from_builder(crate::MailTmClient::builder())
+    }
+
+    /// Builds a client with the same settings as the async one, ie another base url or a proxy
+    pub fn from_builder(builder: MailTmClientBuilder) -> Result<MailTmClient, Error> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        // reqwest needs the runtime to be entered when building its connector
+        let inner = {
+            let _guard = runtime.enter();
+            builder.build()?
+        };
+        Ok(MailTmClient {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// The async client this wraps
+    pub fn inner(&self) -> &crate::MailTmClient {
+        &self.inner
+    }
+
+    pub fn create_account(&self, user: &User) -> Result<Account, Error> {
+        self.runtime.block_on(self.inner.create_account(user))
+    }
+
+    /// Creates an authenticated account, see [`crate::MailTmClient::create_inbox`]
+    pub fn create_inbox(&self) -> Result<Inbox, Error> {
+        Ok(Inbox {
+            inner: self.runtime.block_on(self.inner.create_inbox())?,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    pub fn get_account(&self, user: &User, id: &str) -> Result<Account, Error> {
+        self.runtime.block_on(self.inner.get_account(user, id))
+    }
+
+    pub fn delete_account(&self, user: &User, id: &str) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.delete_account(user, id))
+    }
+
+    pub fn me(&self, user: &User) -> Result<Account, Error> {
+        self.runtime.block_on(self.inner.me(user))
+    }
+
+    pub fn domains(&self) -> Result<HydraCollection<Domain>, Error> {
+        self.runtime.block_on(self.inner.domains())
+    }
+
+    pub fn list_messages(&self, user: &User, page: Option<usize>) -> Result<HydraCollection<MessageSummary>, Error> {
+        self.runtime.block_on(self.inner.list_messages(user, page))
+    }
+
+    /// Iterate over every message, following the pages of the collection
+    pub fn messages_iter(&self, user: &User) -> Iter<MessageSummary> {
+        Iter {
+            stream: Box::pin(self.inner.messages_stream(user)),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    pub fn get_message(&self, user: &User, id: &str) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.get_message(user, id))
+    }
+
+    pub fn fetch_message(&self, user: &User, summary: &MessageSummary) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.fetch_message(user, summary))
+    }
+
+    pub fn delete_message(&self, user: &User, id: &str) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.delete_message(user, id))
+    }
+
+    pub fn patch_message(&self, user: &User, id: &str, patch: &MessagePatch) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.patch_message(user, id, patch))
+    }
+
+    pub fn mark_seen(&self, user: &User, id: &str, seen: bool) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.mark_seen(user, id, seen))
+    }
+
+    pub fn wait_for_message(&self, user: &User, filter: &MessageFilter, timeout: Duration) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.wait_for_message(user, filter, timeout))
+    }
+
+    /// Iterate over real-time updates of the account the token belongs to, blocking until each arrives
+    pub fn events(&self, token: &Token) -> Iter<InboxEvent> {
+        Iter {
+            stream: Box::pin(self.inner.events(token)),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    pub fn source(&self, user: &User, id: &str) -> Result<Source, Error> {
+        self.runtime.block_on(self.inner.source(user, id))
+    }
+
+    pub fn download(&self, user: &User, download_url: &str) -> Result<Bytes, Error> {
+        self.runtime.block_on(self.inner.download(user, download_url))
+    }
+
+    pub fn download_attachment(&self, user: &User, attachment: &Attachment) -> Result<Bytes, Error> {
+        self.runtime.block_on(self.inner.download_attachment(user, attachment))
+    }
+
+    pub fn token(&self, user: &User) -> Result<Token, Error> {
+        self.runtime.block_on(self.inner.token(user))
+    }
+
+    pub fn update_token(&self, user: &User) -> Result<User, Error> {
+        self.runtime.block_on(self.inner.update_token(user))
+    }
+}
+
+/// A blocking [`crate::Inbox`], dropping it blocks until the account is deleted
+#[derive(Debug)]
+pub struct Inbox {
+    inner: crate::Inbox,
+    runtime: Arc<Runtime>,
+}
+
+impl Inbox {
+    /// The async inbox this wraps
+    pub fn inner(&self) -> &crate::Inbox {
+        &self.inner
+    }
+
+    /// Leaves the account in place once the inbox is dropped
+    pub fn keep(self) -> Inbox {
+        Inbox {
+            inner: self.inner.keep(),
+            ..self
+        }
+    }
+
+    pub fn set_keep(&mut self, keep: bool) {
+        self.inner.set_keep(keep);
+    }
+
+    pub fn user(&self) -> &User {
+        self.inner.user()
+    }
+
+    pub fn account(&self) -> &Account {
+        self.inner.account()
+    }
+
+    pub fn token(&self) -> &Token {
+        self.inner.token()
+    }
+
+    pub fn address(&self) -> &str {
+        self.inner.address()
+    }
+
+    pub fn list_messages(&self, page: Option<usize>) -> Result<HydraCollection<MessageSummary>, Error> {
+        self.runtime.block_on(self.inner.list_messages(page))
+    }
+
+    /// Iterate over every message of the inbox
+    pub fn messages_iter(&self) -> Iter<MessageSummary> {
+        Iter {
+            stream: Box::pin(self.inner.messages_stream()),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    pub fn get_message(&self, id: &str) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.get_message(id))
+    }
+
+    pub fn fetch_message(&self, summary: &MessageSummary) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.fetch_message(summary))
+    }
+
+    pub fn delete_message(&self, id: &str) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.delete_message(id))
+    }
+
+    pub fn mark_seen(&self, id: &str, seen: bool) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.mark_seen(id, seen))
+    }
+
+    pub fn wait_for_message(&self, filter: &MessageFilter, timeout: Duration) -> Result<Message, Error> {
+        self.runtime.block_on(self.inner.wait_for_message(filter, timeout))
+    }
+
+    /// Iterate over real-time updates of the inbox, blocking until each arrives
+    pub fn events(&self) -> Iter<InboxEvent> {
+        Iter {
+            stream: Box::pin(self.inner.events()),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    pub fn source(&self, id: &str) -> Result<Source, Error> {
+        self.runtime.block_on(self.inner.source(id))
+    }
+
+    pub fn download(&self, download_url: &str) -> Result<Bytes, Error> {
+        self.runtime.block_on(self.inner.download(download_url))
+    }
+
+    pub fn download_attachment(&self, attachment: &Attachment) -> Result<Bytes, Error> {
+        self.runtime.block_on(self.inner.download_attachment(attachment))
+    }
+
+    /// Deletes the account now, unless it's kept
+    pub fn close(self) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.close())
+    }
+}
+
+type BoxStream<T> = Pin<Box<dyn Stream<Item=Result<T, Error>> + Send>>;
+
+/// A stream of the async client turned into a blocking iterator
+pub struct Iter<T> {
+    stream: BoxStream<T>,
+    runtime: Arc<Runtime>,
+}
+
+impl<T> Iterator for Iter<T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.stream.next())
+    }
+}
+
+/// Creates an account based on a user, see [`crate::create_account`]
+pub fn create_account(user: &User) -> Result<Account, Error> {
+    MailTmClient::new()?.create_account(user)
+}
+
+/// Creates an authenticated account on a random domain, see [`crate::create_inbox`]
+pub fn create_inbox() -> Result<Inbox, Error> {
+    MailTmClient::new()?.create_inbox()
+}
+
+/// Retrieve an account by its id, see [`crate::get_account`]
+pub fn get_account(user: &User, id: &str) -> Result<Account, Error> {
+    MailTmClient::new()?.get_account(user, id)
+}
+
+/// Delete an account by its id, see [`crate::delete_account`]
+pub fn delete_account(user: &User, id: &str) -> Result<(), Error> {
+    MailTmClient::new()?.delete_account(user, id)
+}
+
+/// Retrieve the account belonging to the token holder, see [`crate::me`]
+pub fn me(user: &User) -> Result<Account, Error> {
+    MailTmClient::new()?.me(user)
+}
+
+/// Retrieve all available domains, see [`crate::domains`]
+pub fn domains() -> Result<HydraCollection<Domain>, Error> {
+    MailTmClient::new()?.domains()
+}
+
+/// List messages belonging to the token holder, see [`crate::list_messages`]
+pub fn list_messages(user: &User, page: Option<usize>) -> Result<HydraCollection<MessageSummary>, Error> {
+    MailTmClient::new()?.list_messages(user, page)
+}
+
+/// Retrieve a message by its id, see [`crate::get_message`]
+pub fn get_message(user: &User, id: &str) -> Result<Message, Error> {
+    MailTmClient::new()?.get_message(user, id)
+}
+
+/// Delete a message by its id, see [`crate::delete_message`]
+pub fn delete_message(user: &User, id: &str) -> Result<(), Error> {
+    MailTmClient::new()?.delete_message(user, id)
+}
+
+/// Mark a message as seen or unseen, see [`crate::mark_seen`]
+pub fn mark_seen(user: &User, id: &str, seen: bool) -> Result<Message, Error> {
+    MailTmClient::new()?.mark_seen(user, id, seen)
+}
+
+/// Apply a merge-patch to a message, see [`crate::patch_message`]
+pub fn patch_message(user: &User, id: &str, patch: &MessagePatch) -> Result<Message, Error> {
+    MailTmClient::new()?.patch_message(user, id, patch)
+}
+
+/// Wait until a message matching `filter` arrives, see [`crate::wait_for_message`]
+pub fn wait_for_message(user: &User, filter: &MessageFilter, timeout: Duration) -> Result<Message, Error> {
+    MailTmClient::new()?.wait_for_message(user, filter, timeout)
+}
+
+/// Iterate over real-time updates of an account, see [`crate::events`]
+pub fn events(token: &Token) -> Result<Iter<InboxEvent>, Error> {
+    Ok(MailTmClient::new()?.events(token))
+}
+
+/// Retrieve the raw source of a message by its id, see [`crate::source`]
+pub fn source(user: &User, id: &str) -> Result<Source, Error> {
+    MailTmClient::new()?.source(user, id)
+}
+
+/// Download the raw source of a message, see [`crate::download`]
+pub fn download(user: &User, download_url: &str) -> Result<Bytes, Error> {
+    MailTmClient::new()?.download(user, download_url)
+}
+
+/// Download the bytes of an attachment, see [`crate::download_attachment`]
+pub fn download_attachment(user: &User, attachment: &Attachment) -> Result<Bytes, Error> {
+    MailTmClient::new()?.download_attachment(user, attachment)
+}
+
+/// Retrieve a token for a user, see [`crate::token`]
+pub fn token(user: &User) -> Result<Token, Error> {
+    MailTmClient::new()?.token(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{header, method, path, query_param};
+
+    use super::*;
+
+    #[test]
+    fn test_blocking() -> Result<(), Error> {
+        // the mock server runs on its own runtime, the client blocks the test thread
+        let runtime = tokio::runtime::Runtime::new()?;
+        let server = runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "token": "jwt",
+                    "id": "1"
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/messages"))
+                .and(query_param("page", "1"))
+                .and(header("Authorization", "Bearer jwt"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "hydra:member": [{"id": "1"}, {"id": "2"}],
+                    "hydra:totalItems": 2
+                })))
+                .mount(&server)
+                .await;
+            server
+        });
+
+        let client = MailTmClient::from_builder(crate::MailTmClient::builder().with_base_url(&server.uri()))?;
+        let user = client.update_token(&User::new("someone", "hunter2", "example.test"))?;
+        assert_eq!(user.email_token, "jwt");
+
+        let messages = client.list_messages(&user, Some(1))?;
+        assert_eq!(messages.total_items, 2);
+
+        let ids = client.messages_iter(&user)
+            .map(|message| message.map(|message| message.id2))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(ids, vec!["1", "2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocking_inbox() -> Result<(), Error> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let server = runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/domains"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "hydra:member": [crate::domains::domain("example.test", true, false)],
+                    "hydra:totalItems": 1
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/accounts"))
+                .respond_with(ResponseTemplate::new(201).set_body_json(Account {
+                    address: "generated@example.test".to_string(),
+                    ..crate::accounts::account("1")
+                }))
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "token": "jwt",
+                    "id": "1"
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/messages"))
+                .and(header("Authorization", "Bearer jwt"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "hydra:member": [],
+                    "hydra:totalItems": 0
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("DELETE"))
+                .and(path("/accounts/1"))
+                .respond_with(ResponseTemplate::new(204))
+                .mount(&server)
+                .await;
+            server
+        });
+
+        let client = MailTmClient::from_builder(crate::MailTmClient::builder().with_base_url(&server.uri()))?;
+        let inbox = client.create_inbox()?;
+        assert_eq!(inbox.user().domain, "example.test");
+        assert_eq!(inbox.list_messages(None)?.total_items, 0);
+        drop(inbox);
+
+        // the account is gone by the time the drop returns
+        let deletes = runtime.block_on(server.received_requests()).unwrap_or_default()
+            .into_iter()
+            .filter(|request| request.method == wiremock::http::Method::Delete && request.url.path() == "/accounts/1")
+            .count();
+        assert_eq!(deletes, 1);
+        Ok(())
+    }
+}
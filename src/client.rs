@@ -0,0 +1,995 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use futures_util::stream::Stream;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use tokio::io::AsyncWrite;
+use tokio::time::Duration;
+
+use crate::{accounts, domains, events, http, hydra, messages, sources, token, wait};
+use crate::accounts::Account;
+use crate::error::Error;
+use crate::domains::{Domain, DomainCache, DomainSelector};
+use crate::events::InboxEvent;
+use crate::http::Timeouts;
+use crate::inbox::Inbox;
+use crate::hydra::HydraCollection;
+use crate::messages::{Attachment, Message, MessagePatch, MessageSummary};
+use crate::proxy::Proxy;
+use crate::ratelimit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::sources::Source;
+use crate::token::Token;
+use crate::user::User;
+use crate::wait::MessageFilter;
+use crate::{MAIL_API_URL, MERCURE_URL};
+
+/// How many generated addresses [`MailTmClient::create_inbox`] tries before giving up
+pub const ADDRESS_ATTEMPTS: u32 = 5;
+
+/// The longest a request of a [`MailTmClient::detached`] client may take including retries
+const DETACHED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A long lived Mail-TM client
+///
+/// Owns a single pooled [`http::Client`] and exposes every operation of the API as a method.
+/// Create it once and share it, cloning is cheap and all clones share the same connections.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::MailTmClient;
+/// use mail_tm_rs::user::User;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let client = MailTmClient::builder()
+///         .with_base_url("https://api.mail.gw")
+///         .build()?;
+///     //let user = User::default().with_domain(&client.domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = client.create_account(&user).await?;
+///     //let user = client.update_token(&user).await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MailTmClient {
+    http: http::Client,
+    mercure_url: String,
+    poll_interval: Duration,
+    auto_refresh: bool,
+    refresh_leeway: Duration,
+    refresh_hook: Option<RefreshHook>,
+    tokens: Arc<Mutex<HashMap<String, CachedToken>>>,
+    refreshing: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    proxies: Arc<ProxyPool>,
+    domains: DomainCache,
+    domain_selector: DomainSelector,
+    rng: Arc<Mutex<StdRng>>,
+    builder: Arc<MailTmClientBuilder>,
+}
+
+/// One client per account proxy, along with the proxy each address was assigned
+#[derive(Debug, Default)]
+struct ProxyPool {
+    clients: Vec<http::Client>,
+    assigned: Mutex<HashMap<String, usize>>,
+    next: AtomicUsize,
+}
+
+/// A refreshed token, along with the one it replaced so that one isn't sent again
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    replaced: Option<String>,
+}
+
+/// Builder for a [`MailTmClient`]
+#[derive(Debug, Clone)]
+pub struct MailTmClientBuilder {
+    base_url: String,
+    mercure_url: String,
+    poll_interval: Duration,
+    auto_refresh: bool,
+    refresh_leeway: Duration,
+    refresh_hook: Option<RefreshHook>,
+    retry: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    timeouts: Timeouts,
+    proxy: Option<Proxy>,
+    account_proxies: Vec<Proxy>,
+    domain_ttl: Duration,
+    domain_refresh: bool,
+    domain_selector: DomainSelector,
+    rng_seed: Option<u64>,
+}
+
+/// Called with the user and the new token every time a token is refreshed
+#[derive(Clone)]
+struct RefreshHook(Arc<RefreshFn>);
+
+type RefreshFn = dyn Fn(&User, &Token) + Send + Sync;
+
+impl fmt::Debug for RefreshHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RefreshHook")
+    }
+}
+
+impl Default for MailTmClientBuilder {
+    fn default() -> Self {
+        MailTmClientBuilder {
+            base_url: MAIL_API_URL.to_string(),
+            mercure_url: MERCURE_URL.to_string(),
+            poll_interval: Duration::from_secs(2),
+            auto_refresh: true,
+            refresh_leeway: Duration::from_secs(60),
+            refresh_hook: None,
+            retry: RetryPolicy::default(),
+            rate_limiter: None,
+            timeouts: Timeouts::default(),
+            proxy: None,
+            account_proxies: Vec::new(),
+            domain_ttl: Duration::from_secs(300),
+            domain_refresh: true,
+            domain_selector: DomainSelector::default(),
+            rng_seed: None,
+        }
+    }
+}
+
+impl MailTmClientBuilder {
+    /// Points the client at another deployment of the API
+    pub fn with_base_url(mut self, base_url: &str) -> MailTmClientBuilder {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    /// Points [`MailTmClient::events`] at another Mercure hub, ie `http://localhost:3000/.well-known/mercure`
+    pub fn with_mercure_url(mut self, mercure_url: &str) -> MailTmClientBuilder {
+        self.mercure_url = mercure_url.to_string();
+        self
+    }
+
+    /// How often [`MailTmClient::wait_for_message`] polls the inbox, defaults to 2 seconds
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> MailTmClientBuilder {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Whether expired tokens are transparently refreshed with the user's password, defaults to `true`
+    ///
+    /// Tokens are refreshed before a request when their `exp` claim is within the refresh leeway,
+    /// and once after a request fails with [`Error::Unauthorized`].
+    pub fn with_auto_refresh(mut self, auto_refresh: bool) -> MailTmClientBuilder {
+        self.auto_refresh = auto_refresh;
+        self
+    }
+
+    /// How long before its expiry a token is refreshed, defaults to 60 seconds
+    pub fn with_refresh_leeway(mut self, refresh_leeway: Duration) -> MailTmClientBuilder {
+        self.refresh_leeway = refresh_leeway;
+        self
+    }
+
+    /// Observe token refreshes, ie to persist the new token
+    pub fn with_refresh_hook<F>(mut self, hook: F) -> MailTmClientBuilder
+        where F: Fn(&User, &Token) + Send + Sync + 'static {
+        self.refresh_hook = Some(RefreshHook(Arc::new(hook)));
+        self
+    }
+
+    /// How transient failures are retried, see [`RetryPolicy`] for the defaults
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> MailTmClientBuilder {
+        self.retry = retry;
+        self
+    }
+
+    /// Queues requests through a token bucket instead of letting the API reject them, ie
+    /// [`RateLimiter::mail_tm`]. Off by default, and shared by every clone of the built client.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> MailTmClientBuilder {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Sets every timeout at once, see [`Timeouts`] for the defaults
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> MailTmClientBuilder {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// How long establishing a connection may take, defaults to 10 seconds
+    pub fn with_connect_timeout(mut self, connect: Option<Duration>) -> MailTmClientBuilder {
+        self.timeouts.connect = connect;
+        self
+    }
+
+    /// How long a single attempt of a request may take, defaults to 30 seconds
+    pub fn with_request_timeout(mut self, request: Option<Duration>) -> MailTmClientBuilder {
+        self.timeouts.request = request;
+        self
+    }
+
+    /// How long a request may take including retries, unbounded by default
+    pub fn with_total_timeout(mut self, total: Option<Duration>) -> MailTmClientBuilder {
+        self.timeouts.total = total;
+        self
+    }
+
+    /// How long [`MailTmClient::events`] and streamed downloads may go without receiving data,
+    /// defaults to 90 seconds. The event stream reconnects when it's reached.
+    pub fn with_stream_timeout(mut self, stream: Option<Duration>) -> MailTmClientBuilder {
+        self.timeouts.stream = stream;
+        self
+    }
+
+    /// Routes every request through a proxy, unless its account has one of the account proxies
+    pub fn with_proxy(mut self, proxy: Proxy) -> MailTmClientBuilder {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Spreads accounts over several proxies, ie to give each inbox a different egress
+    ///
+    /// Each address is assigned the next proxy in turn the first time it's used and keeps it, see
+    /// [`MailTmClient::assign_proxy`] to pick one explicitly. Requests that don't belong to an
+    /// account, like listing domains, still go through [`MailTmClientBuilder::with_proxy`]. Every
+    /// proxy gets its own connections and, since quotas are per IP, its own rate limiter.
+    pub fn with_account_proxies(mut self, proxies: Vec<Proxy>) -> MailTmClientBuilder {
+        self.account_proxies = proxies;
+        self
+    }
+
+    /// How long [`MailTmClient::domains`] keeps the domain list, defaults to 5 minutes, zero disables
+    /// the cache
+    pub fn with_domain_ttl(mut self, domain_ttl: Duration) -> MailTmClientBuilder {
+        self.domain_ttl = domain_ttl;
+        self
+    }
+
+    /// Whether the domain list is refreshed in the background before it expires, defaults to `true`
+    pub fn with_domain_refresh(mut self, domain_refresh: bool) -> MailTmClientBuilder {
+        self.domain_refresh = domain_refresh;
+        self
+    }
+
+    /// How [`MailTmClient::create_inbox`] picks a domain, defaults to a random active public one
+    pub fn with_domain_selector(mut self, domain_selector: DomainSelector) -> MailTmClientBuilder {
+        self.domain_selector = domain_selector;
+        self
+    }
+
+    /// Seeds the generated credentials and random domain choices of [`MailTmClient::create_inbox`],
+    /// so a run can be replayed exactly
+    pub fn with_rng_seed(mut self, seed: u64) -> MailTmClientBuilder {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    fn http(&self, proxy: Option<&Proxy>, rate_limiter: Option<RateLimiter>) -> Result<http::Client, Error> {
+        let builder = match proxy {
+            Some(proxy) => proxy.apply(self.timeouts.client_builder())?,
+            None => self.timeouts.client_builder(),
+        };
+        Ok(http::Client::from_builder(builder)?
+            .with_base_url(&self.base_url)
+            .with_retry_policy(self.retry.clone())
+            .with_rate_limiter(rate_limiter)
+            .with_timeouts(self.timeouts.clone()))
+    }
+
+    pub fn build(self) -> Result<MailTmClient, Error> {
+        let builder = Arc::new(self.clone());
+        let http = self.http(self.proxy.as_ref(), self.rate_limiter.clone())?;
+        let clients = self.account_proxies.iter()
+            .map(|proxy| self.http(Some(proxy), self.rate_limiter.as_ref().map(RateLimiter::unshared)))
+            .collect::<Result<_, _>>()?;
+        Ok(MailTmClient {
+            http,
+            mercure_url: self.mercure_url,
+            poll_interval: self.poll_interval,
+            auto_refresh: self.auto_refresh,
+            refresh_leeway: self.refresh_leeway,
+            refresh_hook: self.refresh_hook,
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+            refreshing: Arc::new(Mutex::new(HashMap::new())),
+            proxies: Arc::new(ProxyPool {
+                clients,
+                ..Default::default()
+            }),
+            domains: DomainCache::new(self.domain_ttl).with_background_refresh(self.domain_refresh),
+            domain_selector: self.domain_selector.clone(),
+            rng: Arc::new(Mutex::new(match self.rng_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            })),
+            builder,
+        })
+    }
+}
+
+impl MailTmClient {
+    /// Creates a client for `https://api.mail.tm` with the default settings
+    pub fn new() -> Result<MailTmClient, Error> {
+        MailTmClient::builder().build()
+    }
+
+    pub fn builder() -> MailTmClientBuilder {
+        MailTmClientBuilder::default()
+    }
+
+    /// The underlying http client, for use with the endpoint functions in each module
+    pub fn http(&self) -> &http::Client {
+        &self.http
+    }
+
+    /// The http client requests for an address go through, see [`MailTmClientBuilder::with_account_proxies`]
+    pub fn http_for(&self, address: &str) -> &http::Client {
+        let clients = &self.proxies.clients;
+        if clients.is_empty() || address.is_empty() {
+            return &self.http;
+        }
+
+        let address = address.to_lowercase();
+        let mut assigned = self.proxies.assigned.lock().unwrap();
+        let idx = *assigned.entry(address)
+            .or_insert_with(|| self.proxies.next.fetch_add(1, Ordering::Relaxed) % clients.len());
+        &clients[idx]
+    }
+
+    /// A client with the same settings and proxy assignments but its own connections and rate
+    /// limiter, for use from another runtime. Its requests are bounded by [`DETACHED_TIMEOUT`].
+    pub(crate) fn detached(&self) -> Result<MailTmClient, Error> {
+        let mut builder = self.builder.as_ref().clone();
+        builder.rate_limiter = builder.rate_limiter.as_ref().map(RateLimiter::unshared);
+        builder.timeouts.total = Some(builder.timeouts.total.map_or(DETACHED_TIMEOUT, |total| total.min(DETACHED_TIMEOUT)));
+        let client = builder.build()?;
+        *client.proxies.assigned.lock().unwrap() = self.proxies.assigned.lock().unwrap().clone();
+        Ok(client)
+    }
+
+    /// Pins an address to the account proxy at `index`, returns `false` if there's no such proxy
+    pub fn assign_proxy(&self, address: &str, index: usize) -> bool {
+        if index >= self.proxies.clients.len() {
+            return false;
+        }
+        self.proxies.assigned.lock().unwrap().insert(address.to_lowercase(), index);
+        true
+    }
+
+    /// Drops the cached token and proxy assignment of an address, done when its account is deleted
+    pub fn forget(&self, address: &str) {
+        let address = address.to_lowercase();
+        self.tokens.lock().unwrap().remove(&address);
+        self.refreshing.lock().unwrap().remove(&address);
+        self.proxies.assigned.lock().unwrap().remove(&address);
+    }
+
+    /// The token to authenticate a user with, refreshing it if it's missing or about to expire
+    pub async fn bearer(&self, user: &User) -> Result<String, Error> {
+        if !self.auto_refresh {
+            return Ok(user.email_token.clone());
+        }
+
+        // The user's own token is skipped once it has been replaced, ie after it was rejected
+        let cached = self.cached_token(user);
+        let replaced = cached.as_ref().and_then(|cached| cached.replaced.as_deref());
+        let valid = std::iter::once(user.email_token.clone())
+            .filter(|token| Some(token.as_str()) != replaced)
+            .chain(cached.as_ref().map(|cached| cached.token.clone()))
+            .find(|token| !token.is_empty() && !self.expires_soon(token));
+
+        match valid {
+            Some(token) => Ok(token),
+            None if user.password.is_empty() => Ok(user.email_token.clone()),
+            None => self.refresh_stale(user, &user.email_token).await,
+        }
+    }
+
+    fn cached_token(&self, user: &User) -> Option<CachedToken> {
+        self.tokens.lock().unwrap().get(&user.address()).cloned()
+    }
+
+    fn expires_soon(&self, token: &str) -> bool {
+        token::Claims::decode(token)
+            .map(|claims| claims.expires_within(self.refresh_leeway))
+            .unwrap_or(false)
+    }
+
+    /// Held while a token is refreshed so concurrent requests of a user share a single refresh
+    fn refresh_lock(&self, user: &User) -> Arc<tokio::sync::Mutex<()>> {
+        self.refreshing.lock().unwrap().entry(user.address()).or_default().clone()
+    }
+
+    /// Re-authenticates a user with its password, caching the new token for later requests
+    pub async fn refresh(&self, user: &User) -> Result<String, Error> {
+        let refreshing = self.refresh_lock(user);
+        let _refreshing = refreshing.lock().await;
+        self.fetch_token(user, None).await
+    }
+
+    /// Replaces a missing, expiring or rejected token, unless another request already did
+    async fn refresh_stale(&self, user: &User, stale: &str) -> Result<String, Error> {
+        let refreshing = self.refresh_lock(user);
+        let _refreshing = refreshing.lock().await;
+        // Another request may have refreshed it while this one was waiting
+        if let Some(cached) = self.cached_token(user).filter(|cached| cached.token != stale && !self.expires_soon(&cached.token)) {
+            return Ok(cached.token);
+        }
+        self.fetch_token(user, Some(stale)).await
+    }
+
+    async fn fetch_token(&self, user: &User, replaced: Option<&str>) -> Result<String, Error> {
+        log::debug!("Refreshing token for {}", user.address());
+
+        let token = token::token(self.http_for(&user.address()), user).await?;
+        self.tokens.lock().unwrap().insert(user.address(), CachedToken {
+            token: token.token.clone(),
+            replaced: replaced.filter(|replaced| !replaced.is_empty()).map(str::to_string),
+        });
+        if let Some(RefreshHook(hook)) = &self.refresh_hook {
+            hook(user, &token);
+        }
+        Ok(token.token)
+    }
+
+    /// Runs an authenticated request, refreshing the token and retrying once if it's rejected
+    async fn authed<T, F, Fut>(&self, user: &User, request: F) -> Result<T, Error>
+        where F: Fn(String) -> Fut,
+              Fut: Future<Output=Result<T, Error>> {
+        let bearer = self.bearer(user).await?;
+        match request(bearer.clone()).await {
+            Err(Error::Unauthorized(res)) if self.auto_refresh && !user.password.is_empty() => {
+                log::debug!("Token rejected, refreshing: {}", res);
+                let bearer = self.refresh_stale(user, &bearer).await?;
+                request(bearer).await
+            }
+            result => result,
+        }
+    }
+
+    /// Creates an account based on a user
+    pub async fn create_account(&self, user: &User) -> Result<Account, Error> {
+        accounts::create(self.http_for(&user.address()), user).await
+    }
+
+    /// Creates an account on a domain picked by [`MailTmClientBuilder::with_domain_selector`] and
+    /// returns it authenticated
+    ///
+    /// Credentials are generated with [`User::generate`], and regenerated if the address is
+    /// already taken, up to [`ADDRESS_ATTEMPTS`] times.
+    pub async fn create_inbox(&self) -> Result<Inbox, Error> {
+        let domains = self.domains().await?;
+        let domain = self.domain_selector
+            .select_with(&domains, &mut *self.rng.lock().unwrap())
+            .ok_or(Error::NoDomain)?;
+
+        let mut attempt = 1;
+        loop {
+            let user = User::generate(&mut *self.rng.lock().unwrap()).with_domain(&domain.domain);
+            match self.create_account(&user).await {
+                Ok(account) => {
+                    let token = self.token(&user).await?;
+                    return Ok(Inbox::new(self.clone(), user, account, token));
+                }
+                Err(Error::Validation(e)) if attempt < ADDRESS_ATTEMPTS && e.violations_for("address").next().is_some() => {
+                    log::debug!("Address {} is taken, retrying: {}", user.address(), e.message());
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Retrieve an account by its id, authenticating with [`MailTmClient::bearer`]
+    pub async fn get_account(&self, user: &User, id: &str) -> Result<Account, Error> {
+        self.authed(user, |token| async move {
+            accounts::get(self.http_for(&user.address()), &token, id).await
+        }).await
+    }
+
+    /// Delete an account by its id, authenticating with [`MailTmClient::bearer`]
+    ///
+    /// The user is forgotten afterwards, see [`MailTmClient::forget`].
+    pub async fn delete_account(&self, user: &User, id: &str) -> Result<(), Error> {
+        self.authed(user, |token| async move {
+            accounts::delete(self.http_for(&user.address()), &token, id).await
+        }).await?;
+        self.forget(&user.address());
+        Ok(())
+    }
+
+    /// Retrieve the account belonging to the token holder
+    pub async fn me(&self, user: &User) -> Result<Account, Error> {
+        self.authed(user, |token| async move {
+            accounts::me(self.http_for(&user.address()), &token).await
+        }).await
+    }
+
+    /// Retrieve all available domains, cached for [`MailTmClientBuilder::with_domain_ttl`]
+    pub async fn domains(&self) -> Result<HydraCollection<Domain>, Error> {
+        self.domains.get(&self.http).await
+    }
+
+    /// Drops the cached domain list so the next call to [`MailTmClient::domains`] fetches it again
+    pub fn invalidate_domains(&self) {
+        self.domains.invalidate();
+    }
+
+    /// Stream every available domain, following the pages of the collection
+    pub fn domains_stream(&self) -> impl Stream<Item=Result<Domain, Error>> + Send + 'static {
+        let http = self.http.clone();
+        hydra::paginate(move |page| {
+            let http = http.clone();
+            async move { domains::domains_page(&http, Some(page)).await }
+        })
+    }
+
+    /// List messages belonging to the token holder, `page` defaults to `1`
+    pub async fn list_messages(&self, user: &User, page: Option<usize>) -> Result<HydraCollection<MessageSummary>, Error> {
+        self.authed(user, |token| async move {
+            messages::messages(self.http_for(&user.address()), &token, page).await
+        }).await
+    }
+
+    /// Stream every message belonging to the token holder, following the pages of the collection
+    pub fn messages_stream(&self, user: &User) -> impl Stream<Item=Result<MessageSummary, Error>> + Send + 'static {
+        let client = self.clone();
+        let user = user.clone();
+        hydra::paginate(move |page| {
+            let client = client.clone();
+            let user = user.clone();
+            async move { client.list_messages(&user, Some(page)).await }
+        })
+    }
+
+    /// Retrieve a message by its id
+    pub async fn get_message(&self, user: &User, id: &str) -> Result<Message, Error> {
+        self.authed(user, |token| async move {
+            messages::get(self.http_for(&user.address()), &token, id).await
+        }).await
+    }
+
+    /// Retrieve the full message for a summary from [`MailTmClient::list_messages`]
+    pub async fn fetch_message(&self, user: &User, summary: &MessageSummary) -> Result<Message, Error> {
+        self.get_message(user, &summary.id2).await
+    }
+
+    /// Delete a message by its id
+    pub async fn delete_message(&self, user: &User, id: &str) -> Result<(), Error> {
+        self.authed(user, |token| async move {
+            messages::delete(self.http_for(&user.address()), &token, id).await
+        }).await
+    }
+
+    /// Apply a merge-patch to a message and return the updated message
+    pub async fn patch_message(&self, user: &User, id: &str, patch: &MessagePatch) -> Result<Message, Error> {
+        self.authed(user, |token| async move {
+            messages::patch(self.http_for(&user.address()), &token, id, patch).await
+        }).await
+    }
+
+    /// Mark a message as seen or unseen
+    pub async fn mark_seen(&self, user: &User, id: &str, seen: bool) -> Result<Message, Error> {
+        self.patch_message(user, id, &MessagePatch::seen(seen)).await
+    }
+
+    /// Wait until a message matching `filter` arrives, failing with [`Error::WaitTimeout`] after `timeout`
+    pub async fn wait_for_message(&self, user: &User, filter: &MessageFilter, timeout: Duration) -> Result<Message, Error> {
+        wait::poll(
+            || self.list_messages(user, None),
+            |id| async move { self.get_message(user, &id).await },
+            filter,
+            timeout,
+            self.poll_interval,
+        ).await
+    }
+
+    /// Subscribe to real-time updates of the account the token belongs to
+    ///
+    /// See [`events::events`] for the reconnection behaviour.
+    pub fn events(&self, token: &Token) -> impl Stream<Item=Result<InboxEvent, Error>> + Send + 'static {
+        let address = token.claims().ok().and_then(|claims| claims.username).unwrap_or_default();
+        events::events(self.http_for(&address), &self.mercure_url, &token.token, &token.id)
+    }
+
+    /// Retrieve the raw source of a message by its id
+    pub async fn source(&self, user: &User, id: &str) -> Result<Source, Error> {
+        self.authed(user, |token| async move {
+            sources::source(self.http_for(&user.address()), &token, id).await
+        }).await
+    }
+
+    /// Download the raw source of a message from its [`Message::download_url`]
+    pub async fn download(&self, user: &User, download_url: &str) -> Result<Bytes, Error> {
+        self.authed(user, |token| async move {
+            sources::download(self.http_for(&user.address()), &token, download_url).await
+        }).await
+    }
+
+    /// Stream the raw source of a message from its [`Message::download_url`] into `writer`
+    pub async fn download_to<W>(&self, user: &User, download_url: &str, writer: &mut W) -> Result<u64, Error>
+        where W: AsyncWrite + Unpin + ?Sized {
+        let token = self.bearer(user).await?;
+        sources::download_to(self.http_for(&user.address()), &token, download_url, writer).await
+    }
+
+    /// Download the bytes of an attachment
+    pub async fn download_attachment(&self, user: &User, attachment: &Attachment) -> Result<Bytes, Error> {
+        self.authed(user, |token| async move {
+            messages::attachment(self.http_for(&user.address()), &token, attachment).await
+        }).await
+    }
+
+    /// Stream an attachment into `writer`, ie a `tokio::fs::File`
+    pub async fn download_attachment_to<W>(&self, user: &User, attachment: &Attachment, writer: &mut W) -> Result<u64, Error>
+        where W: AsyncWrite + Unpin + ?Sized {
+        let token = self.bearer(user).await?;
+        messages::attachment_to(self.http_for(&user.address()), &token, attachment, writer).await
+    }
+
+    /// Retrieve a token for a user
+    pub async fn token(&self, user: &User) -> Result<Token, Error> {
+        token::token(self.http_for(&user.address()), user).await
+    }
+
+    /// Retrieves a token for the user and returns a copy of it with the token populated
+    pub async fn update_token(&self, user: &User) -> Result<User, Error> {
+        let token = self.refresh(user).await?;
+        Ok(crate::update_token(user, &token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{body_json, header, method, path};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_client_reuse() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_json(serde_json::json!({
+                "address": "someone@example.test",
+                "password": "hunter2"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token": "jwt",
+                "id": "1"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/accounts/1"))
+            .and(header("Authorization", "Bearer jwt"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .build()?;
+        let user = User::new("someone", "hunter2", "example.test");
+
+        let user = client.update_token(&user).await?;
+        assert_eq!(user.email_token, "jwt");
+
+        client.clone().delete_account(&user, "1").await?;
+        assert!(client.tokens.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh() -> Result<(), Error> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let expired = token::jwt(1);
+        let fresh = token::jwt(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 3_600);
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token": fresh,
+                "id": "1"
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/me"))
+            .and(header("Authorization", format!("Bearer {}", fresh).as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Account {
+                address: "someone@example.test".to_string(),
+                ..Default::default()
+            }))
+            .expect(4)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/me"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                "code": 401,
+                "message": "Invalid JWT Token"
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let counter = refreshes.clone();
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .with_refresh_hook(move |_, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()?;
+
+        // refreshed before the request since the token is expired, then served from the cache
+        let user = crate::update_token(&User::new("someone", "hunter2", "example.test"), &expired);
+        client.me(&user).await?;
+        client.me(&user).await?;
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+
+        // a token without an expiry is used as is, and refreshed once rejected, after which it's
+        // not sent again
+        let user = crate::update_token(&User::new("other", "hunter2", "example.test"), "opaque");
+        client.me(&user).await?;
+        client.me(&user).await?;
+        assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+
+        client.forget("Other@Example.TEST");
+        assert!(!client.tokens.lock().unwrap().contains_key("other@example.test"));
+        assert!(!client.refreshing.lock().unwrap().contains_key("other@example.test"));
+
+        // nothing to refresh with
+        let user = crate::update_token(&User::new("nobody", "", "example.test"), "opaque");
+        assert!(matches!(client.me(&user).await, Err(Error::Unauthorized(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refresh() -> Result<(), Error> {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let fresh = token::jwt(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 3_600);
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({
+                    "token": fresh,
+                    "id": "1"
+                }))
+                .set_delay(Duration::from_millis(100)))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/me"))
+            .and(header("Authorization", format!("Bearer {}", fresh).as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Account::default()))
+            .expect(10)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/me"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/accounts/1"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .build()?;
+
+        // an expired token is refreshed before the requests, a rejected one after, once either way
+        let expired = crate::update_token(&User::new("expired", "hunter2", "example.test"), &token::jwt(1));
+        let rejected = crate::update_token(&User::new("rejected", "hunter2", "example.test"), "opaque");
+        let requests: Vec<_> = (0..10)
+            .map(|i| {
+                let client = client.clone();
+                let user = if i < 5 { expired.clone() } else { rejected.clone() };
+                tokio::spawn(async move { client.me(&user).await })
+            })
+            .collect();
+        for request in requests {
+            request.await.unwrap()?;
+        }
+
+        client.delete_account(&expired, "1").await?;
+        client.delete_account(&rejected, "1").await?;
+        assert!(client.tokens.lock().unwrap().is_empty());
+        assert!(client.refreshing.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_messages_stream() -> Result<(), Error> {
+        use futures_util::TryStreamExt;
+        use wiremock::matchers::query_param;
+
+        let server = MockServer::start().await;
+        let message = messages::message;
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .and(query_param("page", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "hydra:member": [message("1"), message("2")],
+                "hydra:totalItems": 3,
+                "hydra:view": {"@id": "/messages?page=1", "@type": "hydra:PartialCollectionView", "hydra:next": "/messages?page=2"}
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .and(query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "hydra:member": [message("3")],
+                "hydra:totalItems": 3,
+                "hydra:view": {"@id": "/messages?page=2", "@type": "hydra:PartialCollectionView"}
+            })))
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .build()?;
+        let user = crate::update_token(&User::new("someone", "hunter2", "example.test"), "jwt");
+
+        let ids: Vec<String> = client.messages_stream(&user)
+            .map_ok(|message| message.id2)
+            .try_collect()
+            .await?;
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_account_proxies() -> Result<(), Error> {
+        let default = MockServer::start().await;
+        let first = MockServer::start().await;
+        let second = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "hydra:member": [],
+                "hydra:totalItems": 0
+            })))
+            .expect(1)
+            .mount(&default)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/me"))
+            .and(header("Proxy-Authorization", "Basic dXNlcjpodW50ZXIy"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Account::default()))
+            .expect(2)
+            .mount(&first)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/me"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Account::default()))
+            .expect(2)
+            .mount(&second)
+            .await;
+
+        // the mock servers act as http proxies for a host that doesn't exist
+        let client = MailTmClient::builder()
+            .with_base_url("http://api.mail.test")
+            .with_proxy(Proxy::new(&default.uri()))
+            .with_account_proxies(vec![
+                Proxy::new(&first.uri()).with_auth("user", "hunter2"),
+                Proxy::new(&second.uri()),
+            ])
+            .build()?;
+        let user = |id: &str| crate::update_token(&User::new(id, "hunter2", "example.test"), "jwt");
+
+        client.domains().await?;
+        client.me(&user("a")).await?;
+        client.me(&user("b")).await?;
+        client.me(&user("A")).await?;
+
+        assert!(!client.assign_proxy("c@example.test", 2));
+        assert!(client.assign_proxy("c@example.test", 1));
+        client.me(&user("c")).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_inbox() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        let domain = domains::domain;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "hydra:member": [
+                    domain("private.test", true, true),
+                    domain("inactive.test", false, false),
+                    domain("public.test", true, false),
+                ],
+                "hydra:totalItems": 3
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/accounts"))
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "hydra:description": "address: This value is already used.",
+                "violations": [{"propertyPath": "address", "message": "This value is already used."}]
+            })))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/accounts"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(Account {
+                address: "generated@public.test".to_string(),
+                ..accounts::account("1")
+            }))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token": "jwt",
+                "id": "1"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/accounts/1"))
+            .and(header("Authorization", "Bearer jwt"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .build()?;
+        let inbox = client.create_inbox().await?;
+        assert_eq!(inbox.user().domain, "public.test");
+        assert_eq!(inbox.user().email_token, "jwt");
+        assert_eq!(inbox.account().id.as_deref(), Some("1"));
+        inbox.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_inbox_without_domain() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "hydra:member": [],
+                "hydra:totalItems": 0
+            })))
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .build()?;
+        assert!(matches!(client.create_inbox().await, Err(Error::NoDomain)));
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+//! The timestamps of the models
+//!
+//! [`DateTime`] is the same UTC instant whichever features are enabled, and converts to
+//! `chrono::DateTime<Utc>` with the `chrono` feature and to `time::OffsetDateTime` with the `time`
+//! feature. Both can be enabled at once, one of them is required to parse the RFC 3339 timestamps
+//! the API returns.
+//!
+//! # Example
+//! ```
+//! use mail_tm_rs::datetime::DateTime;
+//!
+//! let received: DateTime = "2021-06-01T10:00:00+02:00".parse().unwrap();
+//! assert_eq!(received.unix_timestamp(), 1_622_534_400);
+//! assert_eq!(received.to_string(), "2021-06-01T08:00:00Z");
+//! assert!(received < DateTime::now());
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(not(any(feature = "chrono", feature = "time")))]
+compile_error!("mail-tm-rs needs the `chrono` or the `time` feature to parse timestamps");
+
+/// `0000-01-01T00:00:00Z`, the earliest RFC 3339 timestamp
+const MIN_SECS: i64 = -62_167_219_200;
+/// `9999-12-31T23:59:59Z`, the latest RFC 3339 timestamp
+const MAX_SECS: i64 = 253_402_300_799;
+
+/// A UTC timestamp with nanosecond precision, between the years 0 and 9999 like RFC 3339
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    secs: i64,
+    nanos: u32,
+}
+
+/// A timestamp outside the range of [`DateTime`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutOfRange;
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timestamp out of range")
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+impl DateTime {
+    /// `secs` since the unix epoch plus `nanos`, `None` if it's outside the years 0 to 9999
+    pub fn from_unix(secs: i64, nanos: u32) -> Option<DateTime> {
+        if (MIN_SECS..=MAX_SECS).contains(&secs) && nanos < 1_000_000_000 {
+            Some(DateTime { secs, nanos })
+        } else {
+            None
+        }
+    }
+
+    pub fn now() -> DateTime {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        DateTime {
+            secs: since_epoch.as_secs() as i64,
+            nanos: since_epoch.subsec_nanos(),
+        }
+    }
+
+    /// Seconds since the unix epoch
+    pub fn unix_timestamp(&self) -> i64 {
+        self.secs
+    }
+
+    /// Nanoseconds past the second
+    pub fn nanos(&self) -> u32 {
+        self.nanos
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn to_chrono(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::<chrono::Utc>::from(*self)
+    }
+
+    #[cfg(feature = "time")]
+    pub fn to_time(&self) -> time::OffsetDateTime {
+        time::OffsetDateTime::from(*self)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<DateTime> for chrono::DateTime<chrono::Utc> {
+    fn from(datetime: DateTime) -> Self {
+        use chrono::TimeZone;
+
+        chrono::Utc.timestamp_opt(datetime.secs, datetime.nanos)
+            .single()
+            .expect("the years 0 to 9999 fit chrono")
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TryFrom<chrono::DateTime<chrono::Utc>> for DateTime {
+    type Error = OutOfRange;
+
+    fn try_from(datetime: chrono::DateTime<chrono::Utc>) -> Result<Self, Self::Error> {
+        // leap seconds are reported as nanos past a second
+        DateTime::from_unix(datetime.timestamp(), datetime.timestamp_subsec_nanos().min(999_999_999)).ok_or(OutOfRange)
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<DateTime> for time::OffsetDateTime {
+    fn from(datetime: DateTime) -> Self {
+        let nanos = i128::from(datetime.secs) * 1_000_000_000 + i128::from(datetime.nanos);
+        time::OffsetDateTime::from_unix_timestamp_nanos(nanos).expect("the years 0 to 9999 fit time")
+    }
+}
+
+#[cfg(feature = "time")]
+impl TryFrom<time::OffsetDateTime> for DateTime {
+    type Error = OutOfRange;
+
+    fn try_from(datetime: time::OffsetDateTime) -> Result<Self, Self::Error> {
+        DateTime::from_unix(datetime.unix_timestamp(), datetime.nanosecond()).ok_or(OutOfRange)
+    }
+}
+
+// Either backend parses the same way, chrono is used when both are enabled
+#[cfg(feature = "chrono")]
+mod backend {
+    use std::convert::TryFrom;
+
+    use super::DateTime;
+
+    pub fn parse(text: &str) -> Result<DateTime, String> {
+        let datetime = chrono::DateTime::parse_from_rfc3339(text).map_err(|e| e.to_string())?;
+        DateTime::try_from(datetime.with_timezone(&chrono::Utc)).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(all(feature = "time", not(feature = "chrono")))]
+mod backend {
+    use std::convert::TryFrom;
+
+    use time::format_description::well_known::Rfc3339;
+
+    use super::DateTime;
+
+    pub fn parse(text: &str) -> Result<DateTime, String> {
+        let datetime = time::OffsetDateTime::parse(text, &Rfc3339).map_err(|e| e.to_string())?;
+        DateTime::try_from(datetime).map_err(|e| e.to_string())
+    }
+}
+
+/// Parses an RFC 3339 timestamp, ie `2021-06-01T00:00:00+00:00`
+pub fn parse(text: &str) -> Result<DateTime, String> {
+    backend::parse(text)
+}
+
+impl FromStr for DateTime {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        parse(text)
+    }
+}
+
+/// Formats as RFC 3339 in UTC, ie `2021-06-01T08:00:00Z`, with milli, micro or nanoseconds as needed
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.secs.div_euclid(86_400);
+        let secs = self.secs.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, secs / 3_600, secs / 60 % 60, secs % 60)?;
+        match self.nanos {
+            0 => {}
+            nanos if nanos % 1_000_000 == 0 => write!(f, ".{:03}", nanos / 1_000_000)?,
+            nanos if nanos % 1_000 == 0 => write!(f, ".{:06}", nanos / 1_000)?,
+            nanos => write!(f, ".{:09}", nanos)?,
+        }
+        f.write_str("Z")
+    }
+}
+
+/// The proleptic Gregorian date `days` after the unix epoch, see
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Serialize for DateTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// An RFC 3339 string or a unix timestamp
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Text(String),
+    Timestamp(i64),
+}
+
+impl Raw {
+    fn into_datetime<E: serde::de::Error>(self) -> Result<DateTime, E> {
+        match self {
+            Raw::Text(text) => parse(&text).map_err(E::custom),
+            Raw::Timestamp(secs) => DateTime::from_unix(secs, 0)
+                .ok_or_else(|| E::custom(format!("invalid timestamp {}", secs))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DateTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Raw::deserialize(deserializer)?.into_datetime()
+    }
+}
+
+/// Serde for optional timestamps, treating `null` or an empty string as missing
+pub(crate) mod option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{DateTime, Raw};
+
+    pub fn serialize<S: Serializer>(datetime: &Option<DateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        datetime.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime>, D::Error> {
+        match Option::<Raw>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(Raw::Text(text)) if text.is_empty() => Ok(None),
+            Some(raw) => raw.into_datetime().map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Dated {
+        #[serde(default, with = "option")]
+        at: Option<DateTime>,
+    }
+
+    #[test]
+    fn test_option() -> Result<(), serde_json::Error> {
+        let dated: Dated = serde_json::from_value(serde_json::json!({"at": "2021-06-01T08:00:00+00:00"}))?;
+        assert!(dated.at.is_some());
+        assert_eq!(dated, serde_json::from_str(&serde_json::to_string(&dated)?)?);
+
+        for missing in &[serde_json::json!({}), serde_json::json!({"at": null}), serde_json::json!({"at": ""})] {
+            assert_eq!(serde_json::from_value::<Dated>(missing.clone())?, Dated { at: None });
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<(), serde_json::Error> {
+        let dated: Dated = serde_json::from_value(serde_json::json!({"at": "2021-06-01T10:00:00.5+02:00"}))?;
+        assert_eq!(dated.at, DateTime::from_unix(1_622_534_400, 500_000_000));
+        assert_eq!(dated.at.unwrap().to_string(), "2021-06-01T08:00:00.500Z");
+
+        let epoch: Dated = serde_json::from_value(serde_json::json!({"at": 0}))?;
+        assert_eq!(epoch.at, parse("1970-01-01T00:00:00+00:00").ok());
+        assert!(epoch.at < dated.at);
+
+        assert!(serde_json::from_value::<Dated>(serde_json::json!({"at": "yesterday"})).is_err());
+        assert!(serde_json::from_value::<Dated>(serde_json::json!({"at": i64::MAX})).is_err());
+
+        for text in &["0000-01-01T00:00:00Z", "1969-12-31T23:59:59.000001Z", "2000-02-29T12:30:00Z", "9999-12-31T23:59:59.999999999Z"] {
+            assert_eq!(parse(text).unwrap().to_string(), *text);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversions() {
+        let datetime = DateTime::from_unix(1_622_534_400, 42).unwrap();
+        #[cfg(feature = "chrono")]
+        assert_eq!(DateTime::try_from(datetime.to_chrono()), Ok(datetime));
+        #[cfg(feature = "time")]
+        assert_eq!(DateTime::try_from(datetime.to_time()), Ok(datetime));
+        #[cfg(all(feature = "chrono", feature = "time"))]
+        assert_eq!(datetime.to_chrono().timestamp(), datetime.to_time().unix_timestamp());
+    }
+}
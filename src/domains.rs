@@ -1,10 +1,16 @@
-use anyhow::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::Error;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tokio::time::{Duration, Instant};
 
-use crate::{http, MAIL_API_URL};
+use crate::datetime::DateTime;
+use crate::http;
 use crate::http::Client;
-use crate::hydra::{Search, View, HydraCollection};
+use crate::hydra::HydraCollection;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -20,25 +26,321 @@ pub struct Domain {
     pub domain: String,
     pub is_active: bool,
     pub is_private: bool,
-    pub created_at: String,
-    pub updated_at: String,
+    #[serde(default, with = "crate::datetime::option")]
+    pub created_at: Option<DateTime>,
+    #[serde(default, with = "crate::datetime::option")]
+    pub updated_at: Option<DateTime>,
 }
 
 impl HydraCollection<Domain> {
     pub fn as_list(&self) -> Vec<String> {
         self.members.iter().map(|domain| domain.domain.to_owned()).collect()
     }
+
+    /// Picks a domain with a selector, `None` if no domain is usable
+    pub fn select(&self, selector: &DomainSelector) -> Option<Domain> {
+        selector.select(self)
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn domain(domain: &str, is_active: bool, is_private: bool) -> Domain {
+    Domain {
+        domain: domain.to_string(),
+        is_active,
+        is_private,
+        ..Default::default()
+    }
 }
 
+/// Decides which domain new accounts are created on
+///
+/// Only active public domains that aren't excluded are considered by default. The first
+/// preferred domain that is usable always wins, otherwise one is picked by the strategy: random or
+/// round-robin. Random picks are reproduced by seeding the rng given to
+/// [`DomainSelector::select_with`], which [`MailTmClientBuilder::with_rng_seed`] does for
+/// [`MailTmClient::create_inbox`]. Clones share the round-robin position.
+///
+/// [`MailTmClientBuilder::with_rng_seed`]: crate::MailTmClientBuilder::with_rng_seed
+/// [`MailTmClient::create_inbox`]: crate::MailTmClient::create_inbox
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::domains::DomainSelector;
+/// use mail_tm_rs::MailTmClient;
+///
+/// # fn main() -> Result<(), mail_tm_rs::Error> {
+/// let selector = DomainSelector::round_robin()
+///     .with_preferred(&["favourite.test"])
+///     .with_excluded(&["blocked.test"]);
+/// let client = MailTmClient::builder()
+///     .with_domain_selector(selector)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DomainSelector {
+    active_only: bool,
+    public_only: bool,
+    preferred: Vec<String>,
+    excluded: Vec<String>,
+    strategy: Strategy,
+}
+
+#[derive(Debug, Clone)]
+enum Strategy {
+    Random,
+    RoundRobin(Arc<AtomicUsize>),
+}
+
+impl Default for DomainSelector {
+    fn default() -> Self {
+        DomainSelector::random()
+    }
+}
+
+impl DomainSelector {
+    fn new(strategy: Strategy) -> DomainSelector {
+        DomainSelector {
+            active_only: true,
+            public_only: true,
+            preferred: Vec::new(),
+            excluded: Vec::new(),
+            strategy,
+        }
+    }
+
+    pub fn random() -> DomainSelector {
+        DomainSelector::new(Strategy::Random)
+    }
+
+    pub fn round_robin() -> DomainSelector {
+        DomainSelector::new(Strategy::RoundRobin(Arc::new(AtomicUsize::new(0))))
+    }
+
+    pub fn with_active_only(self, active_only: bool) -> DomainSelector {
+        DomainSelector {
+            active_only,
+            ..self
+        }
+    }
+
+    pub fn with_public_only(self, public_only: bool) -> DomainSelector {
+        DomainSelector {
+            public_only,
+            ..self
+        }
+    }
+
+    /// Domains to use whenever they're usable, in order of preference
+    pub fn with_preferred(self, preferred: &[&str]) -> DomainSelector {
+        DomainSelector {
+            preferred: preferred.iter().map(|domain| domain.to_lowercase()).collect(),
+            ..self
+        }
+    }
+
+    /// Domains to never use
+    pub fn with_excluded(self, excluded: &[&str]) -> DomainSelector {
+        DomainSelector {
+            excluded: excluded.iter().map(|domain| domain.to_lowercase()).collect(),
+            ..self
+        }
+    }
+
+    /// Whether a domain passes the filters
+    pub fn is_usable(&self, domain: &Domain) -> bool {
+        (domain.is_active || !self.active_only)
+            && (!domain.is_private || !self.public_only)
+            && !self.excluded.contains(&domain.domain.to_lowercase())
+    }
+
+    pub fn select(&self, domains: &HydraCollection<Domain>) -> Option<Domain> {
+        self.select_with(domains, &mut rand::thread_rng())
+    }
+
+    /// Like [`DomainSelector::select`] but the random strategy picks with `rng`
+    pub fn select_with<R: Rng + ?Sized>(&self, domains: &HydraCollection<Domain>, rng: &mut R) -> Option<Domain> {
+        let usable: Vec<&Domain> = domains.members.iter().filter(|domain| self.is_usable(domain)).collect();
+        if usable.is_empty() {
+            return None;
+        }
+
+        let preferred = self.preferred.iter()
+            .find_map(|preferred| usable.iter().find(|domain| domain.domain.to_lowercase() == *preferred));
+        if let Some(domain) = preferred {
+            return Some((*domain).clone());
+        }
+
+        let idx = match &self.strategy {
+            Strategy::Random => rng.gen_range(0..usable.len()),
+            Strategy::RoundRobin(next) => next.fetch_add(1, Ordering::Relaxed) % usable.len(),
+        };
+        Some(usable[idx].clone())
+    }
+}
+
+/// Fraction of the ttl after which [`DomainCache`] starts refreshing in the background
+const REFRESH_AHEAD: f64 = 0.75;
+
+/// The domain list of a client, refetched once it's older than the ttl
+///
+/// Tasks asking at the same time while the list has to be fetched share a single request and its
+/// outcome, a failure is returned to each of them as [`Error::Shared`] rather than retried. With
+/// background refresh, the list is refetched on a separate task once it's three quarters of the
+/// way through its ttl so callers keep being served from the cache. Clones share the same cache.
+#[derive(Debug, Clone)]
+pub struct DomainCache {
+    ttl: Duration,
+    background_refresh: bool,
+    cached: Arc<Mutex<Option<Cached>>>,
+    fetching: Arc<Mutex<Option<Arc<Fetch>>>>,
+}
+
+/// The outcome of a fetch, set by whichever of the tasks waiting on it gets to run it
+type Fetch = tokio::sync::OnceCell<Result<HydraCollection<Domain>, Arc<Error>>>;
+
+#[derive(Debug)]
+struct Cached {
+    domains: HydraCollection<Domain>,
+    fetched_at: Instant,
+}
+
+impl DomainCache {
+    /// A cache keeping the list for `ttl`, a zero ttl disables caching
+    pub fn new(ttl: Duration) -> DomainCache {
+        DomainCache {
+            ttl,
+            background_refresh: true,
+            cached: Arc::new(Mutex::new(None)),
+            fetching: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn with_background_refresh(self, background_refresh: bool) -> DomainCache {
+        DomainCache {
+            background_refresh,
+            ..self
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// The cached list, fetching it first if it's missing or expired
+    pub async fn get(&self, client: &Client) -> Result<HydraCollection<Domain>, Error> {
+        if let Some((domains, age)) = self.cached() {
+            if self.background_refresh && age >= self.ttl.mul_f64(REFRESH_AHEAD) {
+                self.refresh_in_background(client);
+            }
+            return Ok(domains);
+        }
 
-// TODO memoise me for some time
-pub(crate) async fn domains() -> Result<HydraCollection<Domain>, Error> {
-    let client = Client::new()?.build()?;
+        let fetch = {
+            let mut fetching = self.fetching.lock().unwrap();
+            // Another task may have fetched the list in the meantime
+            if let Some((domains, _)) = self.cached() {
+                return Ok(domains);
+            }
+            fetching.get_or_insert_with(Default::default).clone()
+        };
+        self.run(client, &fetch).await;
 
+        match Arc::try_unwrap(fetch) {
+            // Nobody else waited on it, so the error can be handed back as is
+            Ok(fetch) => fetch.into_inner()
+                .expect("the fetch has run")
+                .map_err(|e| Arc::try_unwrap(e).unwrap_or_else(Error::Shared)),
+            Err(fetch) => fetch.get()
+                .expect("the fetch has run")
+                .clone()
+                .map_err(Error::Shared),
+        }
+    }
+
+    /// Drops the cached list so the next call fetches it again
+    pub fn invalidate(&self) {
+        self.cached.lock().unwrap().take();
+    }
+
+    /// The cached list and its age, as long as it hasn't expired
+    fn cached(&self) -> Option<(HydraCollection<Domain>, Duration)> {
+        let cached = self.cached.lock().unwrap();
+        let cached = cached.as_ref()?;
+        let age = cached.fetched_at.elapsed();
+        if age < self.ttl {
+            Some((cached.domains.clone(), age))
+        } else {
+            None
+        }
+    }
+
+    /// Runs `fetch` unless another task already has, the first task to see it done lets the next
+    /// call fetch again
+    ///
+    /// Should the task running it be cancelled, one of the others waiting takes over.
+    async fn run(&self, client: &Client, fetch: &Arc<Fetch>) {
+        fetch.get_or_init(|| self.fetch(client)).await;
+
+        let mut fetching = self.fetching.lock().unwrap();
+        if fetching.as_ref().is_some_and(|current| Arc::ptr_eq(current, fetch)) {
+            fetching.take();
+        }
+    }
+
+    async fn fetch(&self, client: &Client) -> Result<HydraCollection<Domain>, Arc<Error>> {
+        let domains = domains(client).await?;
+        *self.cached.lock().unwrap() = Some(Cached {
+            domains: domains.clone(),
+            fetched_at: Instant::now(),
+        });
+        Ok(domains)
+    }
+
+    fn refresh_in_background(&self, client: &Client) {
+        let handle = match Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let fetch = {
+            let mut fetching = self.fetching.lock().unwrap();
+            // Already being fetched
+            if fetching.is_some() {
+                return;
+            }
+            fetching.insert(Arc::new(Fetch::new())).clone()
+        };
+
+        log::debug!("Refreshing domains in the background");
+        let cache = self.clone();
+        let client = client.clone();
+        handle.spawn(async move {
+            cache.run(&client, &fetch).await;
+            if let Some(Err(e)) = fetch.get() {
+                log::debug!("Failed to refresh domains: {}", e);
+            }
+        });
+    }
+}
+
+pub async fn domains(client: &Client) -> Result<HydraCollection<Domain>, Error> {
+    domains_page(client, None).await
+}
+
+pub async fn domains_page(client: &Client, page: Option<usize>) -> Result<HydraCollection<Domain>, Error> {
     log::debug!("Getting domains");
 
-    let response = client
-        .get(&format!("{}/domains", MAIL_API_URL))
+    let builder = client
+        .get("/domains");
+    let builder = if let Some(idx) = page {
+        builder.query(&[("page", idx)])
+    } else {
+        builder
+    };
+
+    let response = builder
         .send()
         .await?;
 
@@ -56,11 +358,14 @@ pub(crate) async fn domains() -> Result<HydraCollection<Domain>, Error> {
 
 #[cfg(test)]
 mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
     use super::*;
 
     #[tokio::test]
     async fn test_domains() -> Result<(), Error> {
-        let domains = domains().await?;
+        let domains = domains(&Client::new()?).await?;
         assert!(domains.total_items > 0);
 
         let first = domains.members.first().unwrap().clone();
@@ -70,4 +375,151 @@ mod tests {
         assert!(domains.contains(&first.domain));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_domains_base_url() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{method, path};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "hydra:member": [{
+                    "@id": "/domains/1",
+                    "@type": "Domain",
+                    "id": "1",
+                    "domain": "example.test",
+                    "isActive": true,
+                    "isPrivate": false,
+                    "createdAt": "2021-06-01T00:00:00+00:00",
+                    "updatedAt": "2021-06-01T00:00:00+00:00"
+                }],
+                "hydra:totalItems": 1
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+        let domains = domains(&client).await?;
+
+        assert_eq!(domains.as_list(), vec!["example.test".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_selector() {
+        let domains = HydraCollection {
+            members: vec![
+                domain("a.test", true, false),
+                domain("inactive.test", false, false),
+                domain("b.test", true, false),
+                domain("private.test", true, true),
+                domain("c.test", true, false),
+            ],
+            ..Default::default()
+        };
+        let picks = |selector: &DomainSelector| (0..6)
+            .map(|_| domains.select(selector).unwrap().domain)
+            .collect::<Vec<_>>();
+
+        assert_eq!(picks(&DomainSelector::round_robin()), vec!["a.test", "b.test", "c.test", "a.test", "b.test", "c.test"]);
+        assert_eq!(picks(&DomainSelector::round_robin().with_excluded(&["B.test"])), vec!["a.test", "c.test", "a.test", "c.test", "a.test", "c.test"]);
+        let seeded = |seed: u64| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..6).map(|_| DomainSelector::random().select_with(&domains, &mut rng).unwrap().domain).collect::<Vec<_>>()
+        };
+        assert_eq!(seeded(7), seeded(7));
+        assert!(picks(&DomainSelector::random()).iter().all(|domain| ["a.test", "b.test", "c.test"].contains(&domain.as_str())));
+
+        let preferred = DomainSelector::random().with_preferred(&["private.test", "missing.test", "c.test", "a.test"]);
+        assert_eq!(picks(&preferred), vec!["c.test"; 6]);
+        let preferred = preferred.with_public_only(false);
+        assert_eq!(domains.select(&preferred).unwrap().domain, "private.test");
+
+        let inactive = DomainSelector::random().with_preferred(&["inactive.test"]).with_active_only(false);
+        assert_eq!(domains.select(&inactive).unwrap().domain, "inactive.test");
+        assert_eq!(domains.select(&DomainSelector::random().with_excluded(&["a.test", "b.test", "c.test"])), None);
+    }
+
+    #[tokio::test]
+    async fn test_domain_cache() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{method, path};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({"hydra:member": [], "hydra:totalItems": 0}))
+                .set_delay(Duration::from_millis(20)))
+            .mount(&server)
+            .await;
+        let requests = || async { server.received_requests().await.unwrap().len() };
+
+        let client = Client::new()?.with_base_url(&server.uri());
+        let cache = DomainCache::new(Duration::from_secs(2));
+
+        // concurrent callers share a single request
+        let shared = cache.clone();
+        let (first, second, third) = tokio::join!(cache.get(&client), cache.get(&client), shared.get(&client));
+        assert_eq!((first?.total_items, second?.total_items, third?.total_items), (0, 0, 0));
+        assert_eq!(requests().await, 1);
+
+        cache.invalidate();
+        cache.get(&client).await?;
+        assert_eq!(requests().await, 2);
+
+        // served from the cache while a refresh runs in the background, halfway between refreshing
+        // ahead at 1.5s and expiring at 2s
+        tokio::time::sleep(Duration::from_millis(1_750)).await;
+        cache.get(&client).await?;
+        assert_eq!(requests().await, 2);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while requests().await < 3 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(requests().await, 3);
+        while cache.fetching.lock().unwrap().is_some() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        cache.get(&client).await?;
+        assert_eq!(requests().await, 3);
+
+        let uncached = DomainCache::new(Duration::from_secs(0));
+        uncached.get(&client).await?;
+        uncached.get(&client).await?;
+        assert_eq!(requests().await, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_domain_cache_failure() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{method, path};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(503).set_delay(Duration::from_millis(20)))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?
+            .with_base_url(&server.uri())
+            .with_retry_policy(crate::retry::RetryPolicy::none());
+        let cache = DomainCache::new(Duration::from_secs(60));
+
+        // concurrent callers share the failure instead of each retrying
+        let (first, second, third) = tokio::join!(cache.get(&client), cache.get(&client), cache.get(&client));
+        for result in &[first, second, third] {
+            assert_eq!(result.as_ref().unwrap_err().status(), Some(503));
+        }
+
+        // the next call fetches again, and gets the error as is when it's alone
+        assert!(matches!(cache.get(&client).await, Err(Error::Server(503, _))));
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -1,7 +1,144 @@
+use std::sync::Arc;
+
+use reqwest::StatusCode;
+use reqwest::header::InvalidHeaderValue;
 use thiserror::Error;
+use tokio::time::Duration;
+
+use crate::hydra::HydraError;
+
+pub type Result<T> = std::result::Result<T, Error>;
 
+/// Every error this crate can return
+///
+/// Non-success responses are split by status so callers can branch on them, anything that doesn't
+/// fit a dedicated variant ends up in [`Error::Status`].
 #[derive(Error, Debug)]
-pub enum HttpError {
+pub enum Error {
+    #[error("Request failed")]
+    Transport(#[source] reqwest::Error),
+    /// A deadline that passed, with the underlying error when reqwest's own timeout fired
+    #[error("Timed out: {0}")]
+    Timeout(String, #[source] Option<reqwest::Error>),
+    #[error("Failed to decode response: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid header value: {0}")]
+    InvalidHeader(#[from] InvalidHeaderValue),
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+    #[error("Invalid credentials: {0}")]
+    InvalidCredentials(String),
+    #[error("Account disabled, status: {0} res: {1}")]
+    AccountDisabled(u16, String),
+    #[error("Unauthorized, res: {0}")]
+    Unauthorized(String),
+    #[error("Not found, res: {0}")]
+    NotFound(String),
+    #[error("Validation failed: {}", .0.message())]
+    Validation(HydraError),
+    #[error("Rate limited, res: {0}")]
+    RateLimited(String),
+    #[error("No usable domain available")]
+    NoDomain,
+    #[error("Timed out after {0:?} waiting for a message")]
+    WaitTimeout(Duration),
+    #[error("Server error, status: {0} res: {1}")]
+    Server(u16, String),
     #[error("Request failed, status: {0} res: {1}")]
     Status(u16, String),
+    /// The error of a request several tasks waited on, ie the domain list
+    #[error(transparent)]
+    Shared(Arc<Error>),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        if e.is_timeout() {
+            Error::Timeout("request timed out".to_string(), Some(e))
+        } else {
+            Error::Transport(e)
+        }
+    }
+}
+
+impl Error {
+    /// Maps a non-success status and its body to the matching variant
+    pub fn from_status(status: &StatusCode, res: &str) -> Error {
+        let res = res.to_string();
+        match *status {
+            StatusCode::UNAUTHORIZED => Error::Unauthorized(res),
+            StatusCode::NOT_FOUND => Error::NotFound(res),
+            StatusCode::UNPROCESSABLE_ENTITY => Error::Validation(HydraError::from_body(&res)),
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited(res),
+            status if status.is_server_error() => Error::Server(status.as_u16(), res),
+            status => Error::Status(status.as_u16(), res),
+        }
+    }
+
+    /// The http status that caused this error, if any
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Transport(e) => e.status().map(|status| status.as_u16()),
+            Error::Unauthorized(_) | Error::InvalidCredentials(_) => Some(StatusCode::UNAUTHORIZED.as_u16()),
+            Error::NotFound(_) => Some(StatusCode::NOT_FOUND.as_u16()),
+            Error::Validation(_) => Some(StatusCode::UNPROCESSABLE_ENTITY.as_u16()),
+            Error::RateLimited(_) => Some(StatusCode::TOO_MANY_REQUESTS.as_u16()),
+            Error::AccountDisabled(status, _) | Error::Server(status, _) | Error::Status(status, _) => Some(*status),
+            Error::Shared(e) => e.status(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_status() {
+        assert!(matches!(Error::from_status(&StatusCode::UNAUTHORIZED, ""), Error::Unauthorized(_)));
+        assert!(matches!(Error::from_status(&StatusCode::NOT_FOUND, ""), Error::NotFound(_)));
+        assert!(matches!(Error::from_status(&StatusCode::UNPROCESSABLE_ENTITY, ""), Error::Validation(_)));
+
+        let res = serde_json::json!({
+            "hydra:description": "address: This value is already used.",
+            "violations": [{"propertyPath": "address", "message": "This value is already used."}]
+        }).to_string();
+        match Error::from_status(&StatusCode::UNPROCESSABLE_ENTITY, &res) {
+            Error::Validation(error) => assert_eq!(error.violations[0].property_path, "address"),
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(matches!(Error::from_status(&StatusCode::TOO_MANY_REQUESTS, ""), Error::RateLimited(_)));
+        assert!(matches!(Error::from_status(&StatusCode::BAD_GATEWAY, ""), Error::Server(502, _)));
+        assert!(matches!(Error::from_status(&StatusCode::FORBIDDEN, "no"), Error::Status(403, ref res) if res == "no"));
+        assert_eq!(Error::from_status(&StatusCode::TOO_MANY_REQUESTS, "").status(), Some(429));
+    }
+
+    #[test]
+    fn test_source() {
+        use std::error::Error as _;
+
+        let e = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let e = Error::from(e);
+        assert!(matches!(e, Error::Transport(_)));
+        assert!(e.source().is_some());
+        // the cause is only printed by whoever walks the source chain
+        assert_eq!(e.to_string(), "Request failed");
+
+        assert!(Error::Timeout("gave up".to_string(), None).source().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_source() {
+        use std::error::Error as _;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let e = reqwest::Client::new().get(&url).timeout(Duration::from_millis(50)).send().await.unwrap_err();
+        let e = Error::from(e);
+        assert!(matches!(e, Error::Timeout(_, Some(_))));
+        assert!(e.source().is_some());
+    }
 }
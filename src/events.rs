@@ -0,0 +1,344 @@
+use std::collections::VecDeque;
+
+use futures_util::stream::{self, Stream};
+use reqwest::header::{ACCEPT, HeaderName};
+use reqwest::Response;
+use tokio::time::Duration;
+
+use crate::accounts::Account;
+use crate::error::Error;
+use crate::http;
+use crate::http::Client;
+use crate::messages::MessageSummary;
+
+/// Default reconnection delay until the hub sends its own `retry`
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+/// A raw server-sent event
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// An update pushed by the hub for an account
+///
+/// The hub publishes the updated resource, its `@type` decides the variant.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InboxEvent {
+    Message(Box<MessageSummary>),
+    Account(Box<Account>),
+    Other(serde_json::Value),
+}
+
+impl InboxEvent {
+    pub fn from_event(event: &Event) -> Result<InboxEvent, Error> {
+        let value: serde_json::Value = serde_json::from_str(&event.data)?;
+        let event = match value.get("@type").and_then(|type_field| type_field.as_str()) {
+            Some("Message") => InboxEvent::Message(Box::new(serde_json::from_value(value)?)),
+            Some("Account") => InboxEvent::Account(Box::new(serde_json::from_value(value)?)),
+            _ => InboxEvent::Other(value),
+        };
+        Ok(event)
+    }
+}
+
+/// Incremental parser for a `text/event-stream` body
+#[derive(Default, Debug)]
+struct Parser {
+    buffer: Vec<u8>,
+    event: Event,
+    has_data: bool,
+    last_event_id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Parser {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+
+            if line.is_empty() {
+                let event = std::mem::take(&mut self.event);
+                if std::mem::take(&mut self.has_data) {
+                    events.push(event);
+                }
+                continue;
+            }
+
+            let (field, value) = match line.find(':') {
+                Some(0) => continue, // comment, used by hubs as a heartbeat
+                Some(idx) => (&line[..idx], line[idx + 1..].strip_prefix(' ').unwrap_or(&line[idx + 1..])),
+                None => (line, ""),
+            };
+            match field {
+                "id" => {
+                    self.event.id = Some(value.to_string());
+                    self.last_event_id = Some(value.to_string());
+                }
+                "event" => self.event.event = Some(value.to_string()),
+                "data" => {
+                    if self.has_data {
+                        self.event.data.push('\n');
+                    }
+                    self.event.data.push_str(value);
+                    self.has_data = true;
+                }
+                "retry" => {
+                    if let Ok(millis) = value.parse() {
+                        self.retry = Some(Duration::from_millis(millis));
+                    }
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Drops any partially received event, used when the connection is lost
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.event = Event::default();
+        self.has_data = false;
+    }
+}
+
+struct State {
+    client: Client,
+    hub_url: String,
+    token: String,
+    topic: String,
+    parser: Parser,
+    response: Option<Response>,
+    pending: VecDeque<Event>,
+    reconnecting: bool,
+    done: bool,
+}
+
+impl State {
+    fn retry(&self) -> Duration {
+        self.parser.retry.unwrap_or(DEFAULT_RETRY)
+    }
+
+    async fn connect(&self) -> Result<Response, Error> {
+        log::debug!("Subscribing to {} from {:?}", self.topic, self.parser.last_event_id);
+
+        let mut builder = self.client
+            .get(&self.hub_url)
+            .bearer_auth(&self.token)
+            .header(ACCEPT, "text/event-stream")
+            .query(&[("topic", &self.topic)])
+            .streaming();
+        if let Some(id) = &self.parser.last_event_id {
+            builder = builder.header(HeaderName::from_static("last-event-id"), id);
+        }
+
+        http::check_response(builder.send().await?).await
+    }
+
+    async fn next(&mut self) -> Option<Result<InboxEvent, Error>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some(event) = self.pending.pop_front() {
+                log::trace!("Received event {:?}", event);
+                return Some(InboxEvent::from_event(&event));
+            }
+
+            let response = match self.response.as_mut() {
+                Some(response) => response,
+                None => {
+                    if self.reconnecting {
+                        tokio::time::sleep(self.retry()).await;
+                    }
+                    self.reconnecting = true;
+                    match self.connect().await {
+                        Ok(response) => {
+                            self.response = Some(response);
+                            continue;
+                        }
+                        // ie a hub that's being deployed or is shedding load
+                        Err(e @ Error::Transport(_))
+                        | Err(e @ Error::Timeout(..))
+                        | Err(e @ Error::Server(..))
+                        | Err(e @ Error::RateLimited(_)) => {
+                            log::debug!("Failed to connect to the hub, retrying: {}", e);
+                            continue;
+                        }
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    }
+                }
+            };
+
+            match self.client.chunk(response).await {
+                Ok(Some(chunk)) => {
+                    let events = self.parser.feed(&chunk);
+                    self.pending.extend(events);
+                }
+                Ok(None) => {
+                    log::debug!("Hub closed the connection, reconnecting");
+                    self.parser.reset();
+                    self.response = None;
+                }
+                Err(e) => {
+                    log::debug!("Lost the connection to the hub, reconnecting: {}", e);
+                    self.parser.reset();
+                    self.response = None;
+                }
+            }
+        }
+    }
+}
+
+/// Subscribes to the updates of an account on a Mercure hub
+///
+/// Reconnects whenever the connection drops, resuming from the last received event with the
+/// `Last-Event-ID` header, or when nothing is received within the client's stream timeout. Failed
+/// connections, `5xx` and `429` responses are retried after the hub's `retry` delay, any other error
+/// ends the stream, ie an expired token.
+pub fn events(client: &Client, hub_url: &str, token: &str, account_id: &str) -> impl Stream<Item=Result<InboxEvent, Error>> + Send + 'static {
+    let state = State {
+        client: client.clone(),
+        hub_url: hub_url.to_string(),
+        token: token.to_string(),
+        topic: format!("/accounts/{}", account_id),
+        parser: Parser::default(),
+        response: None,
+        pending: VecDeque::new(),
+        reconnecting: false,
+        done: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        let next = state.next().await;
+        next.map(|next| (next, state))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{header, method, path, query_param};
+
+    use super::*;
+
+    #[test]
+    fn test_parser() {
+        let mut parser = Parser::default();
+        assert!(parser.feed(b": heartbeat\nid: 1\nda").is_empty());
+
+        let events = parser.feed(b"ta: {\"a\":\r\ndata: 1}\r\n\r\nretry: 10\n\nid: 2\nevent: x\ndata\n\n");
+        assert_eq!(events, vec![
+            Event { id: Some("1".to_string()), event: None, data: "{\"a\":\n1}".to_string() },
+            Event { id: Some("2".to_string()), event: Some("x".to_string()), data: "".to_string() },
+        ]);
+        assert_eq!(parser.last_event_id.as_deref(), Some("2"));
+        assert_eq!(parser.retry, Some(Duration::from_millis(10)));
+    }
+
+    #[tokio::test]
+    async fn test_events() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        let message = serde_json::json!({"@type": "Message", "id": "abc", "intro": "hello"});
+        let account = serde_json::json!({
+            "@type": "Account",
+            "address": "someone@example.test",
+            "quota": 40000000,
+            "used": 100,
+            "isDisabled": false,
+            "createdAt": "2021-06-01T00:00:00+00:00",
+            "updatedAt": "2021-06-01T00:00:00+00:00"
+        });
+        Mock::given(method("GET"))
+            .and(path("/.well-known/mercure"))
+            .and(query_param("topic", "/accounts/1"))
+            .and(header("Last-Event-ID", "2"))
+            .respond_with(ResponseTemplate::new(200)
+                .set_body_raw(format!("id: 3\ndata: {}\n\n", serde_json::json!({"@type": "Other"})), "text/event-stream"))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/mercure"))
+            .and(query_param("topic", "/accounts/1"))
+            .and(header("Authorization", "Bearer jwt"))
+            .respond_with(ResponseTemplate::new(200)
+                .set_body_raw(format!("retry: 10\n\nid: 1\ndata: {}\n\nid: 2\ndata: {}\n\n", message, account), "text/event-stream"))
+            .up_to_n_times(1)
+            .with_priority(2)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?;
+        let hub_url = format!("{}/.well-known/mercure", server.uri());
+        let mut events = Box::pin(events(&client, &hub_url, "jwt", "1"));
+
+        match events.next().await.unwrap()? {
+            InboxEvent::Message(message) => assert_eq!((message.id2.as_str(), message.intro.as_str()), ("abc", "hello")),
+            other => panic!("unexpected event {:?}", other),
+        }
+        match events.next().await.unwrap()? {
+            InboxEvent::Account(account) => assert_eq!(account.used, 100),
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(matches!(events.next().await.unwrap()?, InboxEvent::Other(_)));
+
+        // Resuming from 3 isn't mocked so the hub answers 404 and the stream ends
+        assert!(matches!(events.next().await, Some(Err(Error::NotFound(_)))));
+        assert!(events.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_reconnect() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        let message = |id: &str| format!("id: {}\ndata: {}\n\n", id, serde_json::json!({"@type": "Message", "id": id}));
+        Mock::given(method("GET"))
+            .and(path("/.well-known/mercure"))
+            .and(header("Last-Event-ID", "1"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/mercure"))
+            .and(header("Last-Event-ID", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(message("2"), "text/event-stream"))
+            .with_priority(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/mercure"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(format!("retry: 10\n\n{}", message("1")), "text/event-stream"))
+            .up_to_n_times(1)
+            .with_priority(3)
+            .mount(&server)
+            .await;
+
+        // the hub's 503 isn't retried by the client, the stream reconnects instead
+        let client = Client::new()?.with_retry_policy(crate::retry::RetryPolicy::default().with_max_attempts(1));
+        let hub_url = format!("{}/.well-known/mercure", server.uri());
+        let mut events = Box::pin(events(&client, &hub_url, "jwt", "1"));
+
+        for id in &["1", "2"] {
+            match events.next().await.unwrap()? {
+                InboxEvent::Message(message) => assert_eq!(message.id2, *id),
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        assert!(matches!(events.next().await, Some(Err(Error::NotFound(_)))));
+        Ok(())
+    }
+}
@@ -1,36 +1,337 @@
-use anyhow::Error;
-use reqwest::{Client as ReqwestClient, StatusCode};
-use reqwest::ClientBuilder;
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue, USER_AGENT as USER_AGENT_PARAM};
+use bytes::Bytes;
+use reqwest::{Body, Client as ReqwestClient, ClientBuilder, Method, Response, StatusCode, Url};
+use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, USER_AGENT as USER_AGENT_PARAM};
+use serde::Serialize;
+use tokio::time::Duration;
 
-use crate::error::HttpError;
-use crate::USER_AGENT;
+use crate::error::Error;
+use crate::ratelimit::RateLimiter;
+use crate::retry::{self, RetryPolicy};
+use crate::{MAIL_API_URL, USER_AGENT};
 
+/// Shared HTTP client for every endpoint
+///
+/// Wraps a single pooled [`reqwest::Client`] along with the base url of the API, cloning is cheap
+/// and every clone shares the same connection pool. Defaults to `https://api.mail.tm` but can be
+/// pointed at any deployment running the same software (mail.gw, a staging mirror or a local fake).
+#[derive(Debug, Clone)]
 pub struct Client {
-    headers: HeaderMap<HeaderValue>,
-    builder: ClientBuilder,
+    inner: ReqwestClient,
+    base_url: String,
+    retry: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    timeouts: Timeouts,
+}
+
+/// How long the [`Client`] waits before giving up, `None` waits forever
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::http::Timeouts;
+/// use std::time::Duration;
+///
+/// let timeouts = Timeouts::default()
+///     .with_request(Some(Duration::from_secs(10)))
+///     .with_total(Some(Duration::from_secs(30)));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeouts {
+    /// Establishing a connection, applied when the underlying client is built
+    pub connect: Option<Duration>,
+    /// A single attempt, until the body is read or for streams until the headers are received
+    pub request: Option<Duration>,
+    /// Sending a request including every retry and the delays in between
+    pub total: Option<Duration>,
+    /// The gap between two chunks of a streamed body, ie the event stream or a download
+    pub stream: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            request: Some(Duration::from_secs(30)),
+            total: None,
+            stream: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn with_connect(self, connect: Option<Duration>) -> Timeouts {
+        Timeouts {
+            connect,
+            ..self
+        }
+    }
+
+    pub fn with_request(self, request: Option<Duration>) -> Timeouts {
+        Timeouts {
+            request,
+            ..self
+        }
+    }
+
+    pub fn with_total(self, total: Option<Duration>) -> Timeouts {
+        Timeouts {
+            total,
+            ..self
+        }
+    }
+
+    pub fn with_stream(self, stream: Option<Duration>) -> Timeouts {
+        Timeouts {
+            stream,
+            ..self
+        }
+    }
+
+    /// A reqwest builder with the connect timeout applied
+    pub fn client_builder(&self) -> ClientBuilder {
+        match self.connect {
+            Some(connect) => reqwest::Client::builder().connect_timeout(connect),
+            None => reqwest::Client::builder(),
+        }
+    }
 }
 
 impl Client {
     pub fn new() -> Result<Client, Error> {
-        let client = Client { // TODO: This can be cached
-            headers: get_headers()?,
-            builder: reqwest::Client::builder()
+        Client::from_builder(Timeouts::default().client_builder())
+    }
+
+    /// Finishes a reqwest builder with the headers the API expects
+    pub fn from_builder(builder: ClientBuilder) -> Result<Client, Error> {
+        let client = Client {
+            inner: builder
                 .user_agent(USER_AGENT)
-                .referer(true),
+                .referer(true)
+                .default_headers(get_headers()?)
+                .build()?,
+            base_url: MAIL_API_URL.to_string(),
+            retry: RetryPolicy::default(),
+            rate_limiter: None,
+            timeouts: Timeouts::default(),
         };
         Ok(client)
     }
 
-    pub fn with_auth(mut self, token: &str) -> Result<Client, Error> {
-        self.headers
-            .insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
-        Ok(self)
+    pub fn with_base_url(self, base_url: &str) -> Client {
+        Client {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            ..self
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Client {
+        Client {
+            retry,
+            ..self
+        }
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Queues every request, including retries, through a shared limiter
+    pub fn with_rate_limiter(self, rate_limiter: Option<RateLimiter>) -> Client {
+        Client {
+            rate_limiter,
+            ..self
+        }
+    }
+
+    /// Sets every timeout but the connect one, which is fixed once the client is built, see
+    /// [`Timeouts::client_builder`]
+    pub fn with_timeouts(self, timeouts: Timeouts) -> Client {
+        Client {
+            timeouts,
+            ..self
+        }
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    /// Reads the next chunk of a streamed body, failing if none arrives within the stream timeout
+    pub async fn chunk(&self, response: &mut Response) -> Result<Option<Bytes>, Error> {
+        match self.timeouts.stream {
+            Some(stream) => tokio::time::timeout(stream, response.chunk())
+                .await
+                .map_err(|_| Error::Timeout(format!("no data received for {:?}", stream), None))?
+                .map_err(Error::from),
+            None => Ok(response.chunk().await?),
+        }
+    }
+
+    async fn execute(&self, mut request: reqwest::Request, timeout: Option<Duration>, streaming: bool) -> Result<Response, Error> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+
+        match timeout {
+            Some(timeout) if streaming => tokio::time::timeout(timeout, self.inner.execute(request))
+                .await
+                .map_err(|_| Error::Timeout(format!("no response after {:?}", timeout), None))?
+                .map_err(Error::from),
+            timeout => {
+                *request.timeout_mut() = timeout;
+                Ok(self.inner.execute(request).await?)
+            }
+        }
+    }
+
+    /// Builds an absolute url for an api path, ie `/accounts`, absolute urls are left untouched
+    pub fn url(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            return path.to_string();
+        }
+        format!("{}/{}", self.base_url, path.trim_start_matches('/'))
+    }
+
+    /// Whether a path or url points at the api rather than another host
+    pub fn is_api_url(&self, path: &str) -> bool {
+        match (Url::parse(&self.url(path)), Url::parse(&self.base_url)) {
+            (Ok(url), Ok(base_url)) => url.origin() == base_url.origin(),
+            _ => false,
+        }
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        RequestBuilder {
+            retry: RetryPolicy::is_idempotent(&method),
+            timeout: self.timeouts.request,
+            streaming: false,
+            inner: self.inner.request(method, self.url(path)),
+            client: self.clone(),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.request(Method::GET, path)
+    }
+
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.request(Method::POST, path)
+    }
+
+    pub fn patch(&self, path: &str) -> RequestBuilder {
+        self.request(Method::PATCH, path)
+    }
+
+    pub fn delete(&self, path: &str) -> RequestBuilder {
+        self.request(Method::DELETE, path)
+    }
+}
+
+/// A request that is sent through the [`Client`]'s retry policy
+#[derive(Debug)]
+pub struct RequestBuilder {
+    client: Client,
+    inner: reqwest::RequestBuilder,
+    retry: bool,
+    timeout: Option<Duration>,
+    streaming: bool,
+}
+
+impl RequestBuilder {
+    pub fn bearer_auth(self, token: &str) -> RequestBuilder {
+        RequestBuilder {
+            inner: self.inner.bearer_auth(token),
+            ..self
+        }
+    }
+
+    pub fn header(self, key: HeaderName, value: &str) -> RequestBuilder {
+        RequestBuilder {
+            inner: self.inner.header(key, value),
+            ..self
+        }
+    }
+
+    pub fn query<T: Serialize + ?Sized>(self, query: &T) -> RequestBuilder {
+        RequestBuilder {
+            inner: self.inner.query(query),
+            ..self
+        }
     }
 
-    pub fn build(self) -> Result<ReqwestClient, Error> {
-        Ok(self.builder.default_headers(self.headers).build()?)
+    pub fn body<T: Into<Body>>(self, body: T) -> RequestBuilder {
+        RequestBuilder {
+            inner: self.inner.body(body),
+            ..self
+        }
+    }
+
+    /// Overrides whether transient failures are retried, by default only idempotent methods are
+    pub fn retry(self, retry: bool) -> RequestBuilder {
+        RequestBuilder {
+            retry,
+            ..self
+        }
+    }
+
+    /// Overrides the [`Timeouts::request`] of the client for this request
+    pub fn timeout(self, timeout: Option<Duration>) -> RequestBuilder {
+        RequestBuilder {
+            timeout,
+            ..self
+        }
+    }
+
+    /// Marks the body as read in chunks with [`Client::chunk`], the request timeout then only
+    /// covers receiving the headers
+    pub fn streaming(self) -> RequestBuilder {
+        RequestBuilder {
+            streaming: true,
+            ..self
+        }
+    }
+
+    pub async fn send(self) -> Result<Response, Error> {
+        match self.client.timeouts.total {
+            Some(total) => tokio::time::timeout(total, self.send_with_retries())
+                .await
+                .map_err(|_| Error::Timeout(format!("gave up after {:?}", total), None))?,
+            None => self.send_with_retries().await,
+        }
+    }
+
+    async fn send_with_retries(self) -> Result<Response, Error> {
+        let RequestBuilder { client, inner, retry, timeout, streaming } = self;
+        let request = inner.build()?;
+        let policy = &client.retry;
+        let max_attempts = if retry { policy.max_attempts } else { 1 };
+
+        let mut attempt = 1;
+        loop {
+            // Only bodies that can't be cloned, ie streams, fail here so they're sent once
+            let next = if attempt < max_attempts { request.try_clone() } else { None };
+            let current = match next {
+                Some(next) => next,
+                None => return client.execute(request, timeout, streaming).await,
+            };
+
+            let retry_after = match client.execute(current, timeout, streaming).await {
+                Ok(response) if RetryPolicy::is_transient(response.status()) => retry::retry_after(&response),
+                Err(Error::Timeout(..)) => None,
+                Err(Error::Transport(e)) if e.is_connect() => None,
+                result => return result,
+            };
+
+            let delay = policy.delay(attempt, retry_after);
+            log::debug!("Attempt {} of {} {} failed, retrying in {:?}", attempt, request.method(), request.url(), delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
     }
+
 }
 
 pub fn get_headers() -> Result<HeaderMap<HeaderValue>, Error> {
@@ -38,13 +339,135 @@ pub fn get_headers() -> Result<HeaderMap<HeaderValue>, Error> {
     header_map.insert(USER_AGENT_PARAM, USER_AGENT.parse()?);
     header_map.insert("Origin", "https://mail.tm".parse()?); // TODO test if needed
     header_map.insert("TE", "Trailers".parse()?); // TODO test if needed
-    header_map.insert(CONTENT_TYPE, "application/json;charset=utf-8".parse()?); //TODO memoize me
+    header_map.insert(CONTENT_TYPE, "application/json;charset=utf-8".parse()?);
     Ok(header_map)
 }
 
 pub async fn check_response_status(status: &StatusCode, res: &str) -> Result<(), Error> {
     if !status.is_success() {
-        return Err(HttpError::Status(status.as_u16(), res.to_string()).into());
+        return Err(Error::from_status(status, res));
     }
     Ok(())
 }
+
+/// Checks the status of a response without consuming the body on success, for binary or streamed bodies
+pub async fn check_response(response: Response) -> Result<Response, Error> {
+    let code = response.status();
+    if !code.is_success() {
+        let response = response
+            .text()
+            .await?;
+        return Err(Error::from_status(&code, &response));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() -> Result<(), Error> {
+        let client = Client::new()?;
+        assert_eq!(client.url("/domains"), format!("{}/domains", MAIL_API_URL));
+
+        let client = client.with_base_url("http://127.0.0.1:8080/");
+        assert_eq!(client.base_url(), "http://127.0.0.1:8080");
+        assert_eq!(client.url("/accounts/1"), "http://127.0.0.1:8080/accounts/1");
+        assert_eq!(client.url("me"), "http://127.0.0.1:8080/me");
+        assert_eq!(client.url("https://cdn.test/a.eml"), "https://cdn.test/a.eml");
+
+        assert!(client.is_api_url("/me"));
+        assert!(client.is_api_url("http://127.0.0.1:8080/messages/1/download"));
+        assert!(!client.is_api_url("http://127.0.0.1:8081/messages/1/download"));
+        assert!(!client.is_api_url("https://127.0.0.1:8080/messages/1/download"));
+        assert!(!client.is_api_url("https://cdn.test/a.eml"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry() -> Result<(), Error> {
+        use tokio::time::Duration;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{method, path};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .with_priority(2)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/domains"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/accounts"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?
+            .with_base_url(&server.uri())
+            .with_retry_policy(RetryPolicy::default().with_base_delay(Duration::from_millis(1)));
+
+        assert_eq!(client.get("/domains").send().await?.status(), StatusCode::OK);
+        assert_eq!(client.post("/accounts").send().await?.status(), StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{method, path};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/slow"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/fast"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?
+            .with_base_url(&server.uri())
+            .with_retry_policy(RetryPolicy::none())
+            .with_timeouts(Timeouts::default().with_request(Some(Duration::from_millis(50))));
+        assert!(matches!(client.get("/slow").send().await, Err(Error::Timeout(..))));
+        assert!(matches!(client.get("/slow").streaming().send().await, Err(Error::Timeout(..))));
+        assert_eq!(client.get("/slow").timeout(None).send().await?.status(), StatusCode::OK);
+
+        let mut response = client.get("/fast").streaming().send().await?;
+        assert_eq!(client.chunk(&mut response).await?.as_deref(), Some(&b"ok"[..]));
+
+        // Retries stop once the total timeout is reached
+        let client = client
+            .with_retry_policy(RetryPolicy::default().with_max_attempts(10).with_base_delay(Duration::from_millis(1)))
+            .with_timeouts(Timeouts::default()
+                .with_request(Some(Duration::from_millis(50)))
+                .with_total(Some(Duration::from_millis(120))));
+        match client.get("/slow").send().await {
+            Err(Error::Timeout(message, _)) => assert!(message.starts_with("gave up")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        Ok(())
+    }
+}
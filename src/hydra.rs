@@ -1,5 +1,12 @@
+use std::collections::VecDeque;
+use std::future::Future;
+
+use futures_util::stream::{self, Stream};
 use serde::{Deserialize, Serialize};
 use rand::Rng;
+use rand::seq::SliceRandom;
+
+use crate::error::Error;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -15,8 +22,14 @@ pub struct HydraCollection<T>  {
 }
 
 impl <T: Clone> HydraCollection<T> {
-    pub fn any(&self) -> T {
-        self.members[rand::thread_rng().gen_range(0..self.members.len())].clone()
+    /// A random member, `None` when the collection is empty
+    pub fn any(&self) -> Option<T> {
+        self.any_with(&mut rand::thread_rng())
+    }
+
+    /// A member picked with `rng`, ie a seeded [`rand::rngs::StdRng`] to replay a run
+    pub fn any_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<T> {
+        self.members.choose(rng).cloned()
     }
 }
 
@@ -28,11 +41,80 @@ pub struct View {
     #[serde(rename = "@type")]
     pub type_field: String,
     #[serde(rename = "hydra:first")]
-    pub first: String,
+    pub first: Option<String>,
     #[serde(rename = "hydra:last")]
-    pub last: String,
+    pub last: Option<String>,
+    #[serde(rename = "hydra:previous")]
+    pub previous: Option<String>,
     #[serde(rename = "hydra:next")]
-    pub next: String,
+    pub next: Option<String>,
+}
+
+impl View {
+    /// The page number of the `hydra:next` link, ie `/messages?page=2`
+    pub fn next_page(&self) -> Option<usize> {
+        self.next.as_deref().and_then(page_of)
+    }
+}
+
+fn page_of(link: &str) -> Option<usize> {
+    let (_, query) = link.split_once('?')?;
+    query.split('&')
+        .find_map(|pair| pair.strip_prefix("page="))
+        .and_then(|page| page.parse().ok())
+}
+
+/// Walks every page of a collection, yielding its members one by one
+///
+/// `fetch` is called with the page to retrieve, starting at `1`. The next page is taken from the
+/// `hydra:view` next link when there is one, otherwise pages are incremented until `total_items`
+/// members have been seen. The stream ends after the first error.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::hydra;
+/// use mail_tm_rs::MailTmClient;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let client = MailTmClient::new()?;
+///     let domains = hydra::paginate(move |page| {
+///         let client = client.clone();
+///         async move { mail_tm_rs::domains::domains_page(client.http(), Some(page)).await }
+///     });
+///     //let domains: Vec<_> = futures_util::TryStreamExt::try_collect(domains).await?;
+///     Ok(())
+/// }
+/// ```
+pub fn paginate<T, F, Fut>(fetch: F) -> impl Stream<Item=Result<T, Error>>
+    where F: FnMut(usize) -> Fut,
+          Fut: Future<Output=Result<HydraCollection<T>, Error>> {
+    let state = (fetch, Some(1), 0i64, VecDeque::new());
+
+    stream::unfold(state, |(mut fetch, mut page, mut fetched, mut buffer)| async move {
+        loop {
+            if let Some(member) = buffer.pop_front() {
+                return Some((Ok(member), (fetch, page, fetched, buffer)));
+            }
+            let current = page?;
+
+            log::debug!("Fetching page {}", current);
+            let collection = match fetch(current).await {
+                Ok(collection) => collection,
+                Err(e) => return Some((Err(e), (fetch, None, fetched, buffer))),
+            };
+
+            fetched += collection.members.len() as i64;
+            let exhausted = collection.members.is_empty() || fetched >= collection.total_items;
+            page = match &collection.view {
+                _ if exhausted => None,
+                Some(view) if view.next.is_none() => None,
+                Some(view) => Some(view.next_page().unwrap_or(current + 1)),
+                None => Some(current + 1),
+            };
+            buffer.extend(collection.members);
+        }
+    })
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -58,3 +140,146 @@ pub struct Mapping {
     pub required: bool,
 }
 
+/// An API-Platform error body, ie `hydra:Error` or `ConstraintViolationList`
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HydraError {
+    #[serde(rename = "@context")]
+    pub context: Option<String>,
+    #[serde(rename = "@type")]
+    pub type_field: Option<String>,
+    #[serde(rename = "hydra:title")]
+    pub title: Option<String>,
+    #[serde(rename = "hydra:description")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub violations: Vec<Violation>,
+}
+
+impl HydraError {
+    /// Parses an error body, falling back to using the raw body as the description
+    pub fn from_body(body: &str) -> HydraError {
+        serde_json::from_str(body).unwrap_or_else(|_| HydraError {
+            description: Some(body.to_string()),
+            ..Default::default()
+        })
+    }
+
+    /// The most descriptive message available
+    pub fn message(&self) -> &str {
+        self.description
+            .as_deref()
+            .or(self.title.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Violations for a single property, ie `address`
+    pub fn violations_for<'a>(&'a self, property_path: &'a str) -> impl Iterator<Item=&'a Violation> + 'a {
+        self.violations.iter().filter(move |violation| violation.property_path == property_path)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Violation {
+    pub property_path: String,
+    pub message: String,
+    pub code: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any() {
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+
+        assert_eq!(HydraCollection::<u32>::default().any(), None);
+
+        let collection = HydraCollection {
+            members: (0..100).collect::<Vec<u32>>(),
+            ..Default::default()
+        };
+        assert!(collection.any().is_some());
+
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10).map(|_| collection.any_with(&mut rng).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(picks(7), picks(7));
+    }
+
+    #[test]
+    fn test_hydra_error() {
+        let body = serde_json::json!({
+            "@context": "/contexts/ConstraintViolationList",
+            "@type": "ConstraintViolationList",
+            "hydra:title": "An error occurred",
+            "hydra:description": "address: This value is already used.",
+            "violations": [{
+                "propertyPath": "address",
+                "message": "This value is already used.",
+                "code": "23bd9dbf-6b9b-41cd-a99e-4844bcf3077f"
+            }]
+        }).to_string();
+
+        let error = HydraError::from_body(&body);
+        assert_eq!(error.message(), "address: This value is already used.");
+        assert_eq!(error.violations_for("address").count(), 1);
+        assert_eq!(error.violations_for("password").count(), 0);
+
+        let error = HydraError::from_body("<html>bad gateway</html>");
+        assert_eq!(error.message(), "<html>bad gateway</html>");
+        assert!(error.violations.is_empty());
+    }
+
+    fn fixture(members: &[i32], total_items: i64, next: Option<&str>) -> HydraCollection<i32> {
+        HydraCollection {
+            members: members.to_vec(),
+            total_items,
+            view: Some(View {
+                next: next.map(str::to_string),
+                ..Default::default()
+            }),
+            search: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_paginate() -> Result<(), Error> {
+        use futures_util::TryStreamExt;
+
+        // follows the next links
+        let pages = paginate(|page| async move {
+            Ok(match page {
+                1 => fixture(&[1, 2], 5, Some("/messages?page=3")),
+                3 => fixture(&[3, 4], 5, Some("/messages?page=4")),
+                4 => fixture(&[5], 5, None),
+                _ => unreachable!(),
+            })
+        });
+        assert_eq!(pages.try_collect::<Vec<_>>().await?, vec![1, 2, 3, 4, 5]);
+
+        // increments pages until total items are seen
+        let pages = paginate(|page| async move {
+            let mut collection = fixture(&[page as i32], 3, None);
+            collection.view = None;
+            Ok(collection)
+        });
+        assert_eq!(pages.try_collect::<Vec<_>>().await?, vec![1, 2, 3]);
+
+        // stops after an error
+        let pages = paginate(|page| async move {
+            match page {
+                1 => Ok(fixture(&[1], 2, Some("/messages?page=2"))),
+                _ => Err(Error::NotFound("".to_string())),
+            }
+        });
+        let pages: Vec<_> = futures_util::StreamExt::collect(pages).await;
+        assert_eq!(pages.len(), 2);
+        assert!(matches!(pages[1], Err(Error::NotFound(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use bytes::Bytes;
+use futures_util::stream::Stream;
+use tokio::runtime::{Builder, Handle};
+use tokio::time::Duration;
+
+use crate::accounts::Account;
+use crate::client::MailTmClient;
+use crate::error::Error;
+use crate::events::InboxEvent;
+use crate::hydra::HydraCollection;
+use crate::messages::{Attachment, Message, MessageSummary};
+use crate::sources::Source;
+use crate::token::Token;
+use crate::user::User;
+use crate::wait::MessageFilter;
+
+/// An authenticated account that is deleted once it goes out of scope
+///
+/// Meant for tests, the account is cleaned up even when an assertion panics before the end. Use
+/// [`Inbox::close`] to delete it from async code and see the result, or [`Inbox::keep`] to leave
+/// it around for debugging.
+///
+/// Dropping an open inbox schedules the deletion and returns right away. It runs on the blocking
+/// pool of the current runtime with its own connections and rate limiter, and shutting the runtime
+/// down waits for it, so it also completes at the end of a test or while it panics.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::MailTmClient;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let client = MailTmClient::new()?;
+///     //let inbox = client.create_inbox().await?;
+///     //let messages = inbox.list_messages(None).await?;
+///     //inbox.close().await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Inbox {
+    client: MailTmClient,
+    user: User,
+    account: Account,
+    token: Token,
+    keep: bool,
+    closed: bool,
+}
+
+impl Inbox {
+    /// Takes ownership of a created account, `user` is authenticated with `token`
+    pub fn new(client: MailTmClient, user: User, account: Account, token: Token) -> Inbox {
+        Inbox {
+            user: crate::update_token(&user, &token.token),
+            client,
+            account,
+            token,
+            keep: false,
+            closed: false,
+        }
+    }
+
+    /// Leaves the account in place once the inbox is dropped
+    pub fn keep(mut self) -> Inbox {
+        self.keep = true;
+        self
+    }
+
+    pub fn set_keep(&mut self, keep: bool) {
+        self.keep = keep;
+    }
+
+    pub fn client(&self) -> &MailTmClient {
+        &self.client
+    }
+
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+
+    pub fn address(&self) -> &str {
+        &self.account.address
+    }
+
+    /// List the messages of the inbox, `page` defaults to `1`
+    pub async fn list_messages(&self, page: Option<usize>) -> Result<HydraCollection<MessageSummary>, Error> {
+        self.client.list_messages(&self.user, page).await
+    }
+
+    /// Stream every message of the inbox
+    pub fn messages_stream(&self) -> impl Stream<Item=Result<MessageSummary, Error>> + Send + 'static {
+        self.client.messages_stream(&self.user)
+    }
+
+    pub async fn get_message(&self, id: &str) -> Result<Message, Error> {
+        self.client.get_message(&self.user, id).await
+    }
+
+    pub async fn fetch_message(&self, summary: &MessageSummary) -> Result<Message, Error> {
+        self.client.fetch_message(&self.user, summary).await
+    }
+
+    pub async fn delete_message(&self, id: &str) -> Result<(), Error> {
+        self.client.delete_message(&self.user, id).await
+    }
+
+    pub async fn mark_seen(&self, id: &str, seen: bool) -> Result<Message, Error> {
+        self.client.mark_seen(&self.user, id, seen).await
+    }
+
+    /// Wait until a message matching `filter` arrives, see [`MailTmClient::wait_for_message`]
+    pub async fn wait_for_message(&self, filter: &MessageFilter, timeout: Duration) -> Result<Message, Error> {
+        self.client.wait_for_message(&self.user, filter, timeout).await
+    }
+
+    /// Subscribe to real-time updates of the inbox
+    pub fn events(&self) -> impl Stream<Item=Result<InboxEvent, Error>> + Send + 'static {
+        self.client.events(&self.token)
+    }
+
+    pub async fn source(&self, id: &str) -> Result<Source, Error> {
+        self.client.source(&self.user, id).await
+    }
+
+    pub async fn download(&self, download_url: &str) -> Result<Bytes, Error> {
+        self.client.download(&self.user, download_url).await
+    }
+
+    pub async fn download_attachment(&self, attachment: &Attachment) -> Result<Bytes, Error> {
+        self.client.download_attachment(&self.user, attachment).await
+    }
+
+    /// Deletes the account now, unless it's kept
+    ///
+    /// The inbox still deletes the account when it's dropped if this fails or is cancelled.
+    pub async fn close(mut self) -> Result<(), Error> {
+        if !self.keep {
+            self.client.delete_account(&self.user, &self.account_id()).await?;
+        }
+        self.closed = true;
+        Ok(())
+    }
+
+    fn account_id(&self) -> String {
+        self.account.id.clone().unwrap_or_else(|| self.token.id.clone())
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if self.keep {
+            log::info!("Keeping account {}", self.account.address);
+            return;
+        }
+
+        log::debug!("Deleting account {}", self.account.address);
+        let client = self.client.clone();
+        let user = self.user.clone();
+        let id = self.account_id();
+        let address = self.account.address.clone();
+        let delete = move || match delete_detached(&client, &user, &id) {
+            Ok(()) => client.forget(&user.address()),
+            Err(e) => log::warn!("Failed to delete account {}: {}", address, e),
+        };
+
+        // Unlike a task, blocking work isn't cancelled when the runtime shuts down
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(delete);
+            }
+            Err(_) => {
+                if std::thread::spawn(delete).join().is_err() {
+                    log::warn!("Failed to delete account {}", self.account.address);
+                }
+            }
+        }
+    }
+}
+
+/// Deletes an account on a runtime of its own, with a client that doesn't share connections or a
+/// rate limiter with `client` since its runtime might be busy or gone
+fn delete_detached(client: &MailTmClient, user: &User, id: &str) -> Result<(), Error> {
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(async {
+        client.detached()?.delete_account(user, id).await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::http::Method;
+    use wiremock::matchers::{header, method, path};
+
+    use crate::ratelimit::RateLimiter;
+
+    use super::*;
+
+    fn inbox(client: &MailTmClient, id: &str) -> Inbox {
+        let account = crate::accounts::account(id);
+        let token = Token {
+            token: "jwt".to_string(),
+            id: id.to_string(),
+        };
+        Inbox::new(client.clone(), User::new(id, "hunter2", "example.test"), account, token)
+    }
+
+    /// Waits for the deletions a dropped inbox scheduled, `times` in all
+    async fn deleted(server: &MockServer, id: &str, times: usize) -> bool {
+        let path = format!("/accounts/{}", id);
+        for _ in 0..100 {
+            let requests = server.received_requests().await.unwrap_or_default();
+            if requests.iter().filter(|request| request.method == Method::Delete && request.url.path() == path).count() >= times {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_inbox() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        for (id, deletes) in &[("dropped", 1), ("closed", 1), ("kept", 0)] {
+            Mock::given(method("DELETE"))
+                .and(path(format!("/accounts/{}", id)))
+                .and(header("Authorization", "Bearer jwt"))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(*deletes)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "hydra:member": [],
+                "hydra:totalItems": 0
+            })))
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .build()?;
+
+        let dropped = inbox(&client, "dropped");
+        assert_eq!(dropped.list_messages(None).await?.total_items, 0);
+        drop(dropped);
+        assert!(deleted(&server, "dropped", 1).await);
+
+        inbox(&client, "closed").close().await?;
+        drop(inbox(&client, "kept").keep());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_falls_back_to_drop() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/accounts/failed"))
+            .respond_with(ResponseTemplate::new(403))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/accounts/cancelled"))
+            .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_secs(1)))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .build()?;
+
+        assert!(inbox(&client, "failed").close().await.is_err());
+        let cancelled = tokio::time::timeout(Duration::from_millis(100), inbox(&client, "cancelled").close()).await;
+        assert!(cancelled.is_err());
+
+        // each is deleted again once the inbox is dropped
+        assert!(deleted(&server, "failed", 2).await);
+        assert!(deleted(&server, "cancelled", 2).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop_while_rate_limited() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/me"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Account::default()))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/accounts/dropped"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = MailTmClient::builder()
+            .with_base_url(&server.uri())
+            .with_rate_limiter(RateLimiter::per_second(1))
+            .build()?;
+        let dropped = inbox(&client, "dropped");
+
+        // the second request holds the limiter while it waits for the bucket to refill
+        let user = dropped.user().clone();
+        let busy = client.clone();
+        let requests = tokio::spawn(async move {
+            busy.me(&user).await?;
+            busy.me(&user).await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let start = std::time::Instant::now();
+        drop(dropped);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(deleted(&server, "dropped", 1).await);
+
+        requests.await.unwrap()?;
+        Ok(())
+    }
+}
@@ -1,35 +1,79 @@
 //! Mail-TM API implementation using common HTTP crates
 //!
 //! Provides an implementation of the Mail-TM 2.0.0 API
-//! Largely it is around 80% complete and is missing possibly future deprecations such as sources.
+//! It covers accounts, domains, messages, tokens and message sources.
 //! At present the dependencies are very strict and requires future testing to open it up.
 //!
 //! Expect some breaking changes until v1.0.0 but will try to document them as best I can.
 //!
+//! The functions here are thin wrappers that build a fresh [`MailTmClient`] for `https://api.mail.tm`
+//! on every call. For anything long running, or to target another deployment of the same API
+//! (mail.gw, a staging mirror or a local fake server), build a single [`MailTmClient`] with
+//! [`MailTmClientBuilder::with_base_url`] and reuse it, it shares one connection pool across calls.
+//!
 //! [`Mail-TM`]: https://mail.tm/
 
-use anyhow::{Context, Error};
-
+use bytes::Bytes;
+use events::InboxEvent;
+use inbox::Inbox;
+use futures_util::stream::Stream;
+use sources::Source;
 use token::Token;
-use accounts::Account;
 use tokio::time::Duration;
+use wait::MessageFilter;
+use accounts::Account;
 use user::User;
 use crate::hydra::HydraCollection;
 use crate::domains::Domain;
-use crate::messages::Message;
+use crate::messages::{Attachment, Message, MessagePatch, MessageSummary};
+
+pub use client::{MailTmClient, MailTmClientBuilder};
+pub use error::Error;
 
 pub mod token;
 pub mod accounts;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod client;
+pub mod datetime;
 pub mod domains;
+pub mod events;
 pub mod messages;
+pub mod sources;
 pub mod error;
 pub mod http;
 pub mod hydra;
+pub mod inbox;
+pub mod proxy;
+pub mod ratelimit;
+pub mod retry;
 pub mod user;
+pub mod wait;
 
 pub(crate) const MAIL_API_URL: &str = "https://api.mail.tm";
+pub(crate) const MERCURE_URL: &str = "https://mercure.mail.tm/.well-known/mercure";
 pub(crate) const USER_AGENT: &str = "Reqwest; mail-tm-rs";
 
+/// Creates a ready to use inbox
+///
+/// Picks an active public domain, generates credentials, creates the account and authenticates
+/// it. The account is deleted once the [`Inbox`] is dropped or closed.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::create_inbox;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let inbox = create_inbox().await?;
+///     //let messages = inbox.list_messages(None).await?;
+///     //inbox.close().await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn create_inbox() -> Result<Inbox, Error> {
+    MailTmClient::new()?.create_inbox().await
+}
 
 /// Creates an account based on a user
 ///
@@ -42,14 +86,14 @@ pub(crate) const USER_AGENT: &str = "Reqwest; mail-tm-rs";
 ///
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     //let create = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     Ok(())
 /// }
 /// ```
 pub async fn create_account(user: &User) -> Result<Account, Error> {
-    accounts::create(user).await
+    MailTmClient::new()?.create_account(user).await
 }
 
 /// Retrieve an account
@@ -63,7 +107,7 @@ pub async fn create_account(user: &User) -> Result<Account, Error> {
 ///
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let account = get_account(&user, &account.id.unwrap()).await?;
@@ -71,7 +115,7 @@ pub async fn create_account(user: &User) -> Result<Account, Error> {
 /// }
 /// ```
 pub async fn get_account(user: &User, id: &str) -> Result<Account, Error> {
-    accounts::get(&user.email_token, id).await
+    MailTmClient::new()?.get_account(user, id).await
 }
 
 /// Delete an account
@@ -84,7 +128,7 @@ pub async fn get_account(user: &User, id: &str) -> Result<Account, Error> {
 /// use mail_tm_rs::{create_account, update_token, token, delete_account, domains};
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //delete_account(&user, &account.id.unwrap()).await?;
@@ -92,7 +136,7 @@ pub async fn get_account(user: &User, id: &str) -> Result<Account, Error> {
 /// }
 /// ```
 pub async fn delete_account(user: &User, id: &str) -> Result<(), Error> {
-    accounts::delete(&user.email_token, id).await
+    MailTmClient::new()?.delete_account(user, id).await
 }
 
 /// Retrieve an account
@@ -106,7 +150,7 @@ pub async fn delete_account(user: &User, id: &str) -> Result<(), Error> {
 ///
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let user = me(&user).await?;
@@ -114,7 +158,7 @@ pub async fn delete_account(user: &User, id: &str) -> Result<(), Error> {
 /// }
 /// ```
 pub async fn me(user: &User) -> Result<Account, Error> {
-    accounts::me(&user.email_token).await
+    MailTmClient::new()?.me(user).await
 }
 
 /// Retrieve all available domains
@@ -131,13 +175,14 @@ pub async fn me(user: &User) -> Result<Account, Error> {
 /// }
 /// ```
 pub async fn domains() -> Result<HydraCollection<Domain>, Error> {
-    domains::domains().await
+    MailTmClient::new()?.domains().await
 }
 
 /// List messages
 ///
 /// This will list messages belonging to the token holder. Has a page for optional page selection(inclusive).
-/// Defaults to `1`
+/// Defaults to `1`. Only summaries are listed, use [`get_message`] with [`MessageSummary::id2`] for the
+/// full message.
 ///
 /// # Example
 /// ```
@@ -145,15 +190,15 @@ pub async fn domains() -> Result<HydraCollection<Domain>, Error> {
 /// use mail_tm_rs::{create_account, update_token, token, me, list_messages, domains};
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let messages = list_messages(&user, Some(33)).await?;
 ///     Ok(())
 /// }
 /// ```
-pub async fn list_messages(user: &User, page: Option<usize>) -> Result<HydraCollection<Message>, Error> {
-    messages::messages(&user.email_token, page).await
+pub async fn list_messages(user: &User, page: Option<usize>) -> Result<HydraCollection<MessageSummary>, Error> {
+    MailTmClient::new()?.list_messages(user, page).await
 }
 
 /// Get message
@@ -166,7 +211,7 @@ pub async fn list_messages(user: &User, page: Option<usize>) -> Result<HydraColl
 /// use mail_tm_rs::{create_account, update_token, token, get_message, domains};
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let messages = get_message(&user, "somemessageid").await?;
@@ -174,7 +219,7 @@ pub async fn list_messages(user: &User, page: Option<usize>) -> Result<HydraColl
 /// }
 /// ```
 pub async fn get_message(user: &User, id: &str) -> Result<Message, Error> {
-    messages::get(&user.email_token, id).await
+    MailTmClient::new()?.get_message(user, id).await
 }
 
 /// Delete message
@@ -188,7 +233,7 @@ pub async fn get_message(user: &User, id: &str) -> Result<Message, Error> {
 ///
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let messages = delete_message(&user, "somemessageid").await?;
@@ -196,7 +241,170 @@ pub async fn get_message(user: &User, id: &str) -> Result<Message, Error> {
 /// }
 /// ```
 pub async fn delete_message(user: &User, id: &str) -> Result<(), Error> {
-    messages::delete(&user.email_token, id).await
+    MailTmClient::new()?.delete_message(user, id).await
+}
+
+/// Mark message seen
+///
+/// Mark a message as seen, or unseen, by its id. Returns the updated message.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, mark_seen, domains};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = create_account(&user).await?;
+///     //let user = update_token(&user, &token(&user).await?.token);
+///     //let message = mark_seen(&user, "somemessageid", true).await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn mark_seen(user: &User, id: &str, seen: bool) -> Result<Message, Error> {
+    MailTmClient::new()?.mark_seen(user, id, seen).await
+}
+
+/// Patch message
+///
+/// Apply a merge-patch to a message by its id. Returns the updated message.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::messages::MessagePatch;
+/// use mail_tm_rs::{create_account, update_token, token, patch_message, domains};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = create_account(&user).await?;
+///     //let user = update_token(&user, &token(&user).await?.token);
+///     //let message = patch_message(&user, "somemessageid", &MessagePatch::seen(false)).await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn patch_message(user: &User, id: &str, patch: &MessagePatch) -> Result<Message, Error> {
+    MailTmClient::new()?.patch_message(user, id, patch).await
+}
+
+/// Wait for message
+///
+/// Polls the inbox of the token holder until a message matching the filter arrives, returning it in
+/// full. Fails with [`Error::WaitTimeout`] if nothing matches within `timeout`.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::wait::MessageFilter;
+/// use mail_tm_rs::{create_account, update_token, token, wait_for_message, domains};
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = create_account(&user).await?;
+///     //let user = update_token(&user, &token(&user).await?.token);
+///     let filter = MessageFilter::new().subject_contains("verify your email");
+///     //let message = wait_for_message(&user, &filter, Duration::from_secs(60)).await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn wait_for_message(user: &User, filter: &MessageFilter, timeout: Duration) -> Result<Message, Error> {
+    MailTmClient::new()?.wait_for_message(user, filter, timeout).await
+}
+
+/// Subscribe to inbox events
+///
+/// Streams real-time updates for the account a token belongs to from the Mercure hub, reconnecting
+/// and resuming automatically when the connection drops.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, events, domains};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = create_account(&user).await?;
+///     //let token = token(&user).await?;
+///     //let mut events = Box::pin(events(&token)?);
+///     //while let Some(event) = futures_util::StreamExt::next(&mut events).await {}
+///     Ok(())
+/// }
+/// ```
+pub fn events(token: &Token) -> Result<impl Stream<Item=Result<InboxEvent, Error>> + Send + 'static, Error> {
+    Ok(MailTmClient::new()?.events(token))
+}
+
+/// Get message source
+///
+/// Retrieve the raw RFC 822 source of a message by its id.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, source, domains};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = create_account(&user).await?;
+///     //let user = update_token(&user, &token(&user).await?.token);
+///     //let source = source(&user, "somemessageid").await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn source(user: &User, id: &str) -> Result<Source, Error> {
+    MailTmClient::new()?.source(user, id).await
+}
+
+/// Download message
+///
+/// Download the raw RFC 822 source of a message from its [`Message::download_url`].
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, get_message, download, domains};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = create_account(&user).await?;
+///     //let user = update_token(&user, &token(&user).await?.token);
+///     //let message = get_message(&user, "somemessageid").await?;
+///     //let eml = download(&user, &message.download_url).await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn download(user: &User, download_url: &str) -> Result<Bytes, Error> {
+    MailTmClient::new()?.download(user, download_url).await
+}
+
+/// Download attachment
+///
+/// Download the bytes of one of a message's [`Message::attachments`].
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, get_message, download_attachment, domains};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
+///     //let account = create_account(&user).await?;
+///     //let user = update_token(&user, &token(&user).await?.token);
+///     //let message = get_message(&user, "somemessageid").await?;
+///     //let pdf = download_attachment(&user, &message.attachments[0]).await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn download_attachment(user: &User, attachment: &Attachment) -> Result<Bytes, Error> {
+    MailTmClient::new()?.download_attachment(user, attachment).await
 }
 
 /// Retrieve a token for a user
@@ -211,14 +419,14 @@ pub async fn delete_message(user: &User, id: &str) -> Result<(), Error> {
 ///
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     let user = User::default().with_domain(&domains().await?.any().domain);
+///     let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     let account = create_account(&user).await?;
 ///     let user = update_token(&user, &token(&user).await?.token);
 ///     Ok(())
 /// }
 /// ```
 pub async fn token(user: &User) -> Result<Token, Error> {
-    token::token(user).await
+    MailTmClient::new()?.token(user).await
 }
 
 /// Populates the email token on a user
@@ -232,7 +440,7 @@ pub async fn token(user: &User) -> Result<Token, Error> {
 ///
 /// #[tokio::main]
 /// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     let user = User::default().with_domain(&domains().await?.any().domain);
+///     let user = User::default().with_domain(&domains().await?.any().ok_or("no domains")?.domain);
 ///     let account = create_account(&user).await?;
 ///     let user = update_token(&user, &token(&user).await?.token);
 ///     Ok(())
@@ -244,9 +452,3 @@ pub fn update_token(user: &User, token: &str) -> User {
         ..user.clone()
     }
 }
-
-macro_rules! bo {
-  ($e:expr) => {
-    tokio_test::block_on($e)
-  };
-}
\ No newline at end of file
@@ -1,15 +1,19 @@
-use anyhow::{Context, Error};
+use crate::error::Error;
+use bytes::Bytes;
+use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWrite;
 
-use crate::{http, MAIL_API_URL};
-use crate::http::{Client, get_headers};
+use crate::{http, sources};
+use crate::datetime::DateTime;
+use crate::http::Client;
 use crate::hydra::{HydraCollection, Search, View};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Messages {
     #[serde(rename = "hydra:member")]
-    pub messages: Vec<Message>,
+    pub messages: Vec<MessageSummary>,
     #[serde(rename = "hydra:totalItems")]
     pub total_items: i64,
     #[serde(rename = "hydra:view")]
@@ -43,20 +47,123 @@ pub struct Message {
     #[serde(rename = "verification_results")]
     pub verification_results: Vec<::serde_json::Value>,
     pub retention: bool,
-    #[serde(rename = "retention_date")]
-    pub retention_date: i64,
+    #[serde(rename = "retention_date", default, with = "crate::datetime::option")]
+    pub retention_date: Option<DateTime>,
     pub text: String,
     pub html: Vec<String>,
     #[serde(rename = "has_attachments")]
     pub has_attachments: bool,
-    pub attachments: Vec<::serde_json::Value>,
+    pub attachments: Vec<Attachment>,
     #[serde(rename = "download_url")]
     pub download_url: String,
     pub size: i64,
-    #[serde(rename = "created_at")]
-    pub created_at: String,
-    #[serde(rename = "updated_at")]
-    pub updated_at: String,
+    #[serde(rename = "created_at", default, with = "crate::datetime::option")]
+    pub created_at: Option<DateTime>,
+    #[serde(rename = "updated_at", default, with = "crate::datetime::option")]
+    pub updated_at: Option<DateTime>,
+}
+
+/// A message as listed in the `/messages` collection
+///
+/// The collection only carries a short `intro` instead of the bodies, use
+/// [`crate::MailTmClient::fetch_message`] to retrieve the full [`Message`]. Missing fields are defaulted.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MessageSummary {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub type_field: String,
+    #[serde(rename = "id")]
+    pub id2: String,
+    #[serde(rename = "account_id")]
+    pub account_id: String,
+    #[serde(rename = "msgid")]
+    pub msg_id: String,
+    pub from: From,
+    pub to: Vec<To>,
+    pub subject: String,
+    pub intro: String,
+    pub seen: bool,
+    #[serde(rename = "has_attachments")]
+    pub has_attachments: bool,
+    #[serde(rename = "download_url")]
+    pub download_url: String,
+    pub size: i64,
+    #[serde(rename = "created_at", default, with = "crate::datetime::option")]
+    pub created_at: Option<DateTime>,
+    #[serde(rename = "updated_at", default, with = "crate::datetime::option")]
+    pub updated_at: Option<DateTime>,
+}
+
+impl std::convert::From<&Message> for MessageSummary {
+    fn from(message: &Message) -> MessageSummary {
+        MessageSummary {
+            id: message.id.clone(),
+            type_field: message.type_field.clone(),
+            id2: message.id2.clone(),
+            account_id: message.account_id.clone(),
+            msg_id: message.msg_id.clone(),
+            from: message.from.clone(),
+            to: message.to.clone(),
+            subject: message.subject.clone(),
+            intro: message.text.chars().take(INTRO_LEN).collect(),
+            seen: message.seen,
+            has_attachments: message.has_attachments,
+            download_url: message.download_url.clone(),
+            size: message.size,
+            created_at: message.created_at,
+            updated_at: message.updated_at,
+        }
+    }
+}
+
+impl HydraCollection<MessageSummary> {
+    /// Sorts the messages from oldest to newest, messages without a date come first
+    pub fn sort_by_received(&mut self) {
+        self.members.sort_by_key(|message| message.created_at);
+    }
+
+    /// The messages received after `after`
+    pub fn received_after<'a>(&'a self, after: &'a DateTime) -> impl Iterator<Item=&'a MessageSummary> + 'a {
+        self.members.iter().filter(move |message| message.received_after(after))
+    }
+}
+
+impl MessageSummary {
+    pub fn received_after(&self, after: &DateTime) -> bool {
+        self.created_at.as_ref().map(|created_at| created_at > after).unwrap_or(false)
+    }
+}
+
+impl Message {
+    pub fn received_after(&self, after: &DateTime) -> bool {
+        self.created_at.as_ref().map(|created_at| created_at > after).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn message(id: &str) -> Message {
+    Message {
+        id2: id.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Length of the intro the API generates for a summary
+const INTRO_LEN: usize = 100;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub disposition: String,
+    pub transfer_encoding: String,
+    pub related: bool,
+    pub size: i64,
+    pub download_url: String,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -73,15 +180,14 @@ pub struct To {
     pub name: String,
 }
 
-pub(crate) async fn messages(token: &str, page: Option<usize>) -> Result<HydraCollection<Message>, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
+pub async fn messages(client: &Client, token: &str, page: Option<usize>) -> Result<HydraCollection<MessageSummary>, Error> {
     log::debug!("Getting messages");
 
     let builder = client
-        .get(&format!("{}/messages", MAIL_API_URL));
+        .get("/messages")
+        .bearer_auth(token);
     let builder = if let Some(idx) = page {
-        builder.query(&("page", idx))
+        builder.query(&[("page", idx)])
     } else {
         builder
     };
@@ -98,18 +204,17 @@ pub(crate) async fn messages(token: &str, page: Option<usize>) -> Result<HydraCo
 
     http::check_response_status(&code, &response).await?;
 
-    log::trace!("Retrieved domains: {}", response);
+    log::trace!("Retrieved messages: {}", response);
     Ok(serde_json::from_str(&response)?)
 }
 
-pub(crate) async fn get(token: &str, id: &str) -> Result<Message, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
+pub async fn get(client: &Client, token: &str, id: &str) -> Result<Message, Error> {
     log::debug!("Searching for message with id {}", id);
 
 
     let response = client
-        .get(&format!("{}/messages/{}", MAIL_API_URL, id))
+        .get(&format!("/messages/{}", id))
+        .bearer_auth(token)
         .send()
         .await?;
 
@@ -126,14 +231,13 @@ pub(crate) async fn get(token: &str, id: &str) -> Result<Message, Error> {
 }
 
 
-pub(crate) async fn delete(token: &str, id: &str) -> Result<(), Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
+pub async fn delete(client: &Client, token: &str, id: &str) -> Result<(), Error> {
     log::debug!("Searching for account with id {}", id);
 
 
     let response = client
-        .delete(&format!("{}/messages/{}", MAIL_API_URL, id))
+        .delete(&format!("/messages/{}", id))
+        .bearer_auth(token)
         .send()
         .await?;
 
@@ -145,11 +249,60 @@ pub(crate) async fn delete(token: &str, id: &str) -> Result<(), Error> {
     Ok(())
 }
 
-// TODO impl me
-pub(crate) async fn patch(token: &str, id: &str) -> Result<(), Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
+/// A merge-patch for a message, unset fields are left untouched
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MessagePatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seen: Option<bool>,
+}
 
-    Ok(())
+impl MessagePatch {
+    pub fn seen(seen: bool) -> MessagePatch {
+        MessagePatch {
+            seen: Some(seen),
+        }
+    }
+}
+
+pub async fn patch(client: &Client, token: &str, id: &str, patch: &MessagePatch) -> Result<Message, Error> {
+    log::debug!("Patching message with id {} with {:?}", id, patch);
+
+    let response = client
+        .patch(&format!("/messages/{}", id))
+        .bearer_auth(token)
+        .header(CONTENT_TYPE, "application/merge-patch+json")
+        .body(serde_json::to_string(patch)?)
+        .send()
+        .await?;
+
+    let code = response.status();
+
+    let response = response
+        .text()
+        .await?;
+
+    http::check_response_status(&code, &response).await?;
+
+    log::trace!("Patched a message: {}", response);
+    // mail.tm only echoes the patched fields back, so the message is fetched again
+    if serde_json::from_str::<MessagePatch>(&response).is_ok() {
+        return get(client, token, id).await;
+    }
+    Ok(serde_json::from_str(&response)?)
+}
+
+/// Downloads an attachment's bytes
+pub async fn attachment(client: &Client, token: &str, attachment: &Attachment) -> Result<Bytes, Error> {
+    log::debug!("Downloading attachment {}", attachment.filename);
+    sources::download(client, token, &attachment.download_url).await
+}
+
+/// Streams an attachment into `writer`, returning the number of bytes written
+pub async fn attachment_to<W>(client: &Client, token: &str, attachment: &Attachment, writer: &mut W) -> Result<u64, Error>
+    where W: AsyncWrite + Unpin + ?Sized {
+    log::debug!("Streaming attachment {}", attachment.filename);
+    sources::download_to(client, token, &attachment.download_url, writer).await
 }
 
 #[cfg(test)]
@@ -163,18 +316,174 @@ mod tests {
     #[tokio::test]
     async fn test_messages() -> Result<(), Error> {
         pretty_env_logger::try_init().ok();
-        let user = User::default().with_domain(&crate::domains::domains().await?.any().domain);
-        let create = create(&user).await.unwrap();
+        let client = Client::new()?;
+        let user = User::default().with_domain(&crate::domains::domains(&client).await?.any().unwrap().domain);
+        let create = create(&client, &user).await.unwrap();
         let token = crate::token(&user).await.unwrap();
 
 
-        let messages = messages(&token.token, None).await?;
+        let messages = messages(&client, &token.token, None).await?;
         assert_eq!(messages.total_items, 0);
 
         let id = create.id.unwrap();
 
-        accounts::delete(&token.token, &id).await.unwrap();
+        accounts::delete(&client, &token.token, &id).await.unwrap();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_patch() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{body_json, header, method, path};
+
+        let server = MockServer::start().await;
+        let mut message = serde_json::to_value(message("abc"))?;
+        message["seen"] = serde_json::json!(true);
+        Mock::given(method("PATCH"))
+            .and(path("/messages/abc"))
+            .and(header("Authorization", "Bearer jwt"))
+            .and(header("Content-Type", "application/merge-patch+json"))
+            .and(body_json(serde_json::json!({"seen": true})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(message))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+        let message = patch(&client, "jwt", "abc", &MessagePatch::seen(true)).await?;
+
+        assert_eq!(message.id2, "abc");
+        assert!(message.seen);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_patch_echo() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{method, path};
+
+        let server = MockServer::start().await;
+        Mock::given(method("PATCH"))
+            .and(path("/messages/abc"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"seen": true})))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/messages/abc"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(Message {
+                seen: true,
+                ..message("abc")
+            }))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/messages/broken"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"seen": true, "subject": 1})))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/messages/broken"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+
+        // the echo mail.tm answers with is completed with the full message
+        let message = patch(&client, "jwt", "abc", &MessagePatch::seen(true)).await?;
+        assert_eq!(message.id2, "abc");
+        assert!(message.seen);
+
+        // anything else that doesn't decode is an error
+        let broken = patch(&client, "jwt", "broken", &MessagePatch::seen(true)).await;
+        assert!(matches!(broken, Err(Error::Decode(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attachments() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{header, method, path};
+
+        let server = MockServer::start().await;
+        let mut message = serde_json::to_value(Message::default())?;
+        message["has_attachments"] = serde_json::json!(true);
+        message["attachments"] = serde_json::json!([{
+            "id": "ATTACH000001",
+            "filename": "invoice.pdf",
+            "contentType": "application/pdf",
+            "disposition": "attachment",
+            "transferEncoding": "base64",
+            "related": false,
+            "size": 8,
+            "downloadUrl": "/messages/abc/attachment/ATTACH000001"
+        }]);
+        let message: Message = serde_json::from_value(message)?;
+        let pdf = message.attachments.first().unwrap();
+        assert_eq!(pdf.content_type, "application/pdf");
+
+        Mock::given(method("GET"))
+            .and(path("/messages/abc/attachment/ATTACH000001"))
+            .and(header("Authorization", "Bearer jwt"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("%PDF-1.4", "application/pdf"))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+        assert_eq!(attachment(&client, "jwt", pdf).await?, "%PDF-1.4".as_bytes());
+
+        let mut file = Vec::new();
+        assert_eq!(attachment_to(&client, "jwt", pdf, &mut file).await?, pdf.size as u64);
+        assert_eq!(file, b"%PDF-1.4");
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary() -> Result<(), Error> {
+        let summary: MessageSummary = serde_json::from_value(serde_json::json!({
+            "@id": "/messages/abc",
+            "@type": "Message",
+            "id": "abc",
+            "from": {"address": "noreply@example.com", "name": "Example"},
+            "subject": "Verify your email",
+            "intro": "Your code is 1234",
+            "seen": false
+        }))?;
+        assert_eq!(summary.id2, "abc");
+        assert_eq!(summary.intro, "Your code is 1234");
+        assert!(summary.to.is_empty());
+
+        let message = Message {
+            text: "x".repeat(INTRO_LEN * 2),
+            ..message("abc")
+        };
+        let summary = MessageSummary::from(&message);
+        assert_eq!(summary.id2, "abc");
+        assert_eq!(summary.intro.len(), INTRO_LEN);
+        Ok(())
+    }
 
+    #[test]
+    fn test_received_after() -> Result<(), Error> {
+        let mut messages: HydraCollection<MessageSummary> = serde_json::from_value(serde_json::json!({
+            "hydra:member": [
+                {"id": "new", "created_at": "2021-06-03T00:00:00+00:00"},
+                {"id": "undated"},
+                {"id": "old", "created_at": "2021-06-01T00:00:00+00:00"}
+            ],
+            "hydra:totalItems": 3
+        }))?;
+        messages.sort_by_received();
+        let ids = messages.members.iter().map(|message| message.id2.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["undated", "old", "new"]);
+
+        let after = crate::datetime::parse("2021-06-02T00:00:00+00:00").unwrap();
+        let ids = messages.received_after(&after).map(|message| message.id2.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["new"]);
         Ok(())
     }
 
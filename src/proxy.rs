@@ -0,0 +1,65 @@
+use std::fmt;
+
+use reqwest::ClientBuilder;
+
+use crate::error::Error;
+
+/// A proxy requests are routed through, `http`, `https` and `socks5` urls are supported
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::proxy::Proxy;
+/// use mail_tm_rs::MailTmClient;
+///
+/// # fn main() -> Result<(), mail_tm_rs::Error> {
+/// let client = MailTmClient::builder()
+///     .with_proxy(Proxy::new("socks5://127.0.0.1:1080").with_auth("user", "hunter2"))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, PartialEq)]
+pub struct Proxy {
+    url: String,
+    auth: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn new(url: &str) -> Proxy {
+        Proxy {
+            url: url.to_string(),
+            auth: None,
+        }
+    }
+
+    /// Authenticates with the proxy, as basic auth for http proxies or the socks5 username/password
+    pub fn with_auth(self, username: &str, password: &str) -> Proxy {
+        Proxy {
+            auth: Some((username.to_string(), password.to_string())),
+            ..self
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Routes every request of a reqwest builder through this proxy
+    pub fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder, Error> {
+        let proxy = reqwest::Proxy::all(self.url.as_str())?;
+        let proxy = match &self.auth {
+            Some((username, password)) => proxy.basic_auth(username, password),
+            None => proxy,
+        };
+        Ok(builder.proxy(proxy))
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("url", &self.url)
+            .field("username", &self.auth.as_ref().map(|(username, _)| username))
+            .finish()
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// The request rate mail.tm allows per IP
+pub const MAIL_TM_REQUESTS_PER_SECOND: u32 = 8;
+
+/// A token bucket shared by every clone of a client
+///
+/// Each request takes a token, when the bucket is empty requests queue up in order until it
+/// refills instead of failing with `429`. Allows bursts of up to `requests` at once.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::ratelimit::RateLimiter;
+/// use mail_tm_rs::MailTmClient;
+///
+/// # fn main() -> Result<(), mail_tm_rs::Error> {
+/// let client = MailTmClient::builder()
+///     .with_rate_limiter(RateLimiter::per_second(4))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    capacity: f64,
+    per_second: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Allows `requests` every `per`
+    pub fn new(requests: u32, per: Duration) -> RateLimiter {
+        let capacity = f64::from(requests.max(1));
+        RateLimiter {
+            capacity,
+            per_second: capacity / per.as_secs_f64().max(f64::EPSILON),
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: capacity,
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn per_second(requests: u32) -> RateLimiter {
+        RateLimiter::new(requests, Duration::from_secs(1))
+    }
+
+    /// Matches the published mail.tm quota, see [`MAIL_TM_REQUESTS_PER_SECOND`]
+    pub fn mail_tm() -> RateLimiter {
+        RateLimiter::per_second(MAIL_TM_REQUESTS_PER_SECOND)
+    }
+
+    /// A limiter with the same quota that doesn't share this one's bucket
+    pub fn unshared(&self) -> RateLimiter {
+        RateLimiter {
+            capacity: self.capacity,
+            per_second: self.per_second,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: self.capacity,
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Waits until a request is allowed
+    pub async fn acquire(&self) {
+        // Holding the lock while waiting keeps callers in order
+        let mut bucket = self.bucket.lock().await;
+        loop {
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.per_second;
+            bucket.tokens = (bucket.tokens + refill).min(self.capacity);
+            bucket.refilled_at = now;
+
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return;
+            }
+
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second);
+            log::trace!("Rate limited, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limiter = RateLimiter::new(2, Duration::from_millis(100));
+        let start = Instant::now();
+
+        // the burst goes straight through
+        limiter.acquire().await;
+        limiter.clone().acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(40));
+
+        // the rest is spaced out at 50ms, across clones
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire().await })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(140));
+    }
+}
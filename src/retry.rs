@@ -0,0 +1,153 @@
+use rand::Rng;
+use reqwest::{Method, Response, StatusCode};
+use reqwest::header::RETRY_AFTER;
+use tokio::time::Duration;
+
+/// How transient failures are retried
+///
+/// Only idempotent requests (GET, HEAD, PUT, DELETE, OPTIONS) and token requests are retried by
+/// default, account creation has to be opted into with [`RetryPolicy::with_retry_account_creation`].
+/// A request is retried on connection errors, timeouts, `429` and `5xx` responses, waiting for
+/// `base_delay * 2^(attempt - 1)` capped at `max_delay`, so the first retry waits `base_delay`, or
+/// for the `Retry-After` seconds when present.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::retry::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::default()
+///     .with_max_attempts(5)
+///     .with_base_delay(Duration::from_millis(250))
+///     .with_retry_account_creation(true);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomised, `0.0` disables jitter
+    pub jitter: f64,
+    pub retry_account_creation: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            retry_account_creation: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Total attempts including the first one
+    pub fn with_max_attempts(self, max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_base_delay(self, base_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            base_delay,
+            ..self
+        }
+    }
+
+    pub fn with_max_delay(self, max_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_delay,
+            ..self
+        }
+    }
+
+    pub fn with_jitter(self, jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            jitter: jitter.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    pub fn with_retry_account_creation(self, retry_account_creation: bool) -> RetryPolicy {
+        RetryPolicy {
+            retry_account_creation,
+            ..self
+        }
+    }
+
+    pub fn is_idempotent(method: &Method) -> bool {
+        matches!(*method, Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS)
+    }
+
+    pub fn is_transient(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
+
+    /// The delay before retrying after `attempt` failed attempts
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+
+        let backoff = self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        if self.jitter > 0.0 {
+            backoff.mul_f64(1.0 - self.jitter * rand::thread_rng().gen::<f64>())
+        } else {
+            backoff
+        }
+    }
+}
+
+/// The `Retry-After` of a response, only the delay in seconds form is supported
+pub(crate) fn retry_after(response: &Response) -> Option<Duration> {
+    response.headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default()
+            .with_base_delay(Duration::from_millis(100))
+            .with_max_delay(Duration::from_millis(350))
+            .with_jitter(0.0);
+
+        assert_eq!(policy.delay(1, None), Duration::from_millis(100));
+        assert_eq!(policy.delay(2, None), Duration::from_millis(200));
+        assert_eq!(policy.delay(3, None), Duration::from_millis(350));
+        assert_eq!(policy.delay(40, None), Duration::from_millis(350));
+        assert_eq!(policy.delay(1, Some(Duration::from_millis(10))), Duration::from_millis(10));
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(10))), Duration::from_millis(350));
+
+        let policy = policy.with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(1, None);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::error::Error;
+use crate::http;
+use crate::http::Client;
+
+/// The raw RFC 822 source of a message
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Source {
+    #[serde(rename = "@context")]
+    pub context: Option<String>,
+    #[serde(rename = "@id")]
+    pub at_id: Option<String>,
+    #[serde(rename = "@type")]
+    pub type_field: Option<String>,
+    pub id: String,
+    pub download_url: String,
+    pub data: String,
+}
+
+pub async fn source(client: &Client, token: &str, id: &str) -> Result<Source, Error> {
+    log::debug!("Getting source for message with id {}", id);
+
+    let response = client
+        .get(&format!("/sources/{}", id))
+        .bearer_auth(token)
+        .send()
+        .await?;
+
+    let code = response.status();
+
+    let response = response
+        .text()
+        .await?;
+
+    http::check_response_status(&code, &response).await?;
+
+    log::trace!("Retrieved a source: {}", response);
+    Ok(serde_json::from_str(&response)?)
+}
+
+/// Downloads a raw message from its `download_url`, which can be relative to the base url or absolute
+///
+/// The token is only sent along when the url points at the api.
+pub async fn download(client: &Client, token: &str, download_url: &str) -> Result<Bytes, Error> {
+    log::debug!("Downloading {}", download_url);
+
+    let response = download_request(client, token, download_url)
+        .send()
+        .await?;
+
+    let response = http::check_response(response).await?;
+
+    Ok(response.bytes().await?)
+}
+
+/// Streams a raw message from its `download_url` into `writer`, returning the number of bytes written
+pub async fn download_to<W>(client: &Client, token: &str, download_url: &str, writer: &mut W) -> Result<u64, Error>
+    where W: AsyncWrite + Unpin + ?Sized {
+    log::debug!("Streaming {}", download_url);
+
+    let response = download_request(client, token, download_url)
+        .streaming()
+        .send()
+        .await?;
+
+    let mut response = http::check_response(response).await?;
+
+    let mut written = 0;
+    while let Some(chunk) = client.chunk(&mut response).await? {
+        writer.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    writer.flush().await?;
+
+    log::trace!("Streamed {} bytes from {}", written, download_url);
+    Ok(written)
+}
+
+/// Only authenticates downloads from the api, an absolute `download_url` on another host mustn't
+/// receive the token
+fn download_request(client: &Client, token: &str, download_url: &str) -> http::RequestBuilder {
+    let request = client.get(download_url);
+    if client.is_api_url(download_url) {
+        request.bearer_auth(token)
+    } else {
+        log::debug!("Not sending the token to {}", download_url);
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{header, header_exists, method, path};
+
+    use super::*;
+
+    const RAW: &str = "From: someone@example.test\r\nSubject: hi\r\n\r\nhello\r\n";
+
+    #[tokio::test]
+    async fn test_sources() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/sources/abc"))
+            .and(header("Authorization", "Bearer jwt"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "@context": "/contexts/Source",
+                "@id": "/sources/abc",
+                "@type": "Source",
+                "id": "abc",
+                "downloadUrl": "/messages/abc/download",
+                "data": RAW
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/messages/abc/download"))
+            .and(header("Authorization", "Bearer jwt"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(RAW, "message/rfc822"))
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+
+        let source = source(&client, "jwt", "abc").await?;
+        assert_eq!(source.data, RAW);
+
+        let raw = download(&client, "jwt", &source.download_url).await?;
+        assert_eq!(raw, RAW.as_bytes());
+
+        let mut streamed = Vec::new();
+        let written = download_to(&client, "jwt", &format!("{}/messages/abc/download", server.uri()), &mut streamed).await?;
+        assert_eq!(written, RAW.len() as u64);
+        assert_eq!(streamed, RAW.as_bytes());
+
+        assert!(matches!(download(&client, "jwt", "/messages/missing/download").await, Err(Error::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_foreign_host() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        let foreign = MockServer::start().await;
+        Mock::given(header_exists("Authorization"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&foreign)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/abc.eml"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(RAW, "message/rfc822"))
+            .expect(2)
+            .mount(&foreign)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+        let url = format!("{}/abc.eml", foreign.uri());
+
+        assert_eq!(download(&client, "jwt", &url).await?, RAW.as_bytes());
+        let mut streamed = Vec::new();
+        download_to(&client, "jwt", &url, &mut streamed).await?;
+        assert_eq!(streamed, RAW.as_bytes());
+        Ok(())
+    }
+}
@@ -1,9 +1,12 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 
-use crate::http::{Client};
+use crate::http;
+use crate::http::Client;
 use crate::user::User;
-use crate::MAIL_API_URL;
-use anyhow::Error;
+use crate::error::Error;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -12,46 +15,192 @@ pub struct Token {
     pub id: String,
 }
 
-pub(crate) async fn token(user: &User) -> Result<Token, Error> {
-    let client = Client::new()?
-        .build()?;
+/// The claims of a mail-tm JWT
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Claims {
+    pub iat: Option<u64>,
+    pub exp: Option<u64>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub username: Option<String>,
+    pub id: Option<String>,
+}
+
+impl Claims {
+    /// Decodes the payload of a JWT without verifying its signature
+    pub fn decode(jwt: &str) -> Result<Claims, Error> {
+        let payload = jwt.split('.')
+            .nth(1)
+            .ok_or_else(|| Error::InvalidToken("missing payload".to_string()))?;
+        let payload = base64::decode_config(payload.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+            .map_err(|e| Error::InvalidToken(e.to_string()))?;
+        Ok(serde_json::from_slice(&payload)?)
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.exp.map(|exp| UNIX_EPOCH + Duration::from_secs(exp))
+    }
+
+    /// Whether the token expires within `leeway` from now, tokens without an expiry never do
+    pub fn expires_within(&self, leeway: Duration) -> bool {
+        match self.expires_at() {
+            Some(expires_at) => expires_at <= SystemTime::now() + leeway,
+            None => false,
+        }
+    }
+}
+
+impl Token {
+    pub fn claims(&self) -> Result<Claims, Error> {
+        Claims::decode(&self.token)
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.claims().ok().and_then(|claims| claims.expires_at())
+    }
+}
 
+pub async fn token(client: &Client, user: &User) -> Result<Token, Error> {
     log::debug!("Getting token for user {:?}", user);
 
     let create_as_string = serde_json::json!({
-        "address": format!("{}@{}", user.id, user.domain).to_lowercase(),
+        "address": user.address(),
         "password": user.password
     });
 
     let res = client
-        .post(format!("{}/token", MAIL_API_URL).as_str())
+        .post("/token")
+        .retry(true)
         .body(create_as_string.to_string())
         .send()
         .await?;
 
+    let code = res.status();
+
     let body = res.text().await?;
-    log::trace!("Retrieved email token: {:?}", body);
 
+    check_token_status(&code, &body).await?;
+
+    log::trace!("Retrieved email token: {:?}", body);
     Ok(serde_json::from_str(&body)?)
 }
 
+/// The body of a rejected authentication, ie `{"code": 401, "message": "Invalid credentials."}`
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthError {
+    code: Option<u16>,
+    message: Option<String>,
+}
+
+async fn check_token_status(status: &StatusCode, res: &str) -> Result<(), Error> {
+    let message = || serde_json::from_str::<AuthError>(res)
+        .ok()
+        .and_then(|error| error.message)
+        .unwrap_or_else(|| res.to_string());
+
+    match *status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN if message().to_lowercase().contains("disabled") => {
+            Err(Error::AccountDisabled(status.as_u16(), message()))
+        }
+        StatusCode::UNAUTHORIZED => Err(Error::InvalidCredentials(message())),
+        _ => http::check_response_status(status, res).await,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn jwt(exp: u64) -> String {
+    let encode = |json: serde_json::Value| base64::encode_config(json.to_string(), base64::URL_SAFE_NO_PAD);
+    format!(
+        "{}.{}.signature",
+        encode(serde_json::json!({"typ": "JWT", "alg": "RS256"})),
+        encode(serde_json::json!({"iat": 1, "exp": exp, "roles": ["ROLE_USER"], "username": "someone@example.test", "id": "1"}))
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::accounts;
 
+    #[test]
+    fn test_claims() -> Result<(), Error> {
+        let claims = Claims::decode(&jwt(1_000))?;
+        assert_eq!(claims.id.as_deref(), Some("1"));
+        assert_eq!(claims.expires_at(), Some(UNIX_EPOCH + Duration::from_secs(1_000)));
+        assert!(claims.expires_within(Duration::from_secs(0)));
+
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 3_600;
+        let token = Token {
+            token: jwt(exp),
+            id: "1".to_string(),
+        };
+        assert!(!token.claims()?.expires_within(Duration::from_secs(60)));
+        assert!(token.claims()?.expires_within(Duration::from_secs(7_200)));
+
+        assert!(matches!(Claims::decode("not a jwt"), Err(Error::InvalidToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_token_errors() -> Result<(), Error> {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{body_partial_json, method, path};
+
+        let server = MockServer::start().await;
+        let respond = |address: &str, response: ResponseTemplate| {
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .and(body_partial_json(serde_json::json!({"address": address})))
+                .respond_with(response)
+        };
+        respond("ok@example.test", ResponseTemplate::new(200).set_body_json(serde_json::json!({"token": "jwt", "id": "1"})))
+            .mount(&server).await;
+        respond("wrong@example.test", ResponseTemplate::new(401).set_body_json(serde_json::json!({"code": 401, "message": "Invalid credentials."})))
+            .mount(&server).await;
+        respond("disabled@example.test", ResponseTemplate::new(401).set_body_json(serde_json::json!({"code": 401, "message": "Account is disabled."})))
+            .mount(&server).await;
+        respond("locked@example.test", ResponseTemplate::new(403).set_body_json(serde_json::json!({"code": 403, "message": "Account is disabled."})))
+            .mount(&server).await;
+        respond("busy@example.test", ResponseTemplate::new(429).set_body_string("Too Many Requests"))
+            .mount(&server).await;
+
+        let client = Client::new()?
+            .with_base_url(&server.uri())
+            .with_retry_policy(crate::retry::RetryPolicy::none());
+        let user = |id: &str| User::new(id, "hunter2", "example.test");
+
+        assert_eq!(token(&client, &user("ok")).await?.token, "jwt");
+        match token(&client, &user("wrong")).await {
+            Err(Error::InvalidCredentials(message)) => assert_eq!(message, "Invalid credentials."),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match token(&client, &user("disabled")).await {
+            Err(e @ Error::AccountDisabled(..)) => assert_eq!(e.status(), Some(401)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match token(&client, &user("locked")).await {
+            Err(e @ Error::AccountDisabled(..)) => assert_eq!(e.status(), Some(403)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(token(&client, &user("busy")).await, Err(Error::RateLimited(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_token() -> Result<(), Error> {
         pretty_env_logger::try_init().ok();
-        let user = User::default().with_domain(&crate::domains::domains().await?.any().domain);
+        let client = Client::new()?;
+        let user = User::default().with_domain(&crate::domains::domains(&client).await?.any().unwrap().domain);
 
-        let create = accounts::create(&user).await.unwrap();
+        let create = accounts::create(&client, &user).await.unwrap();
 
-        let token = token(&user).await.unwrap();
+        let token = token(&client, &user).await.unwrap();
 
-        assert_eq!(token.token.is_empty(), false);
+        assert!(!token.token.is_empty());
 
-        accounts::delete(&token.token, &create.id.unwrap()).await.unwrap();
+        accounts::delete(&client, &token.token, &create.id.unwrap()).await.unwrap();
 
         Ok(())
     }
@@ -39,18 +39,45 @@ impl User {
         }
     }
 
-    fn get_random_string(len: usize) -> String {
-        rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+    /// The full email address, ie `id@domain`
+    pub fn address(&self) -> String {
+        format!("{}@{}", self.id, self.domain).to_lowercase()
     }
-}
 
-impl Default for User {
-    fn default() -> Self {
+    /// Random credentials generated with `rng`, ie a seeded [`rand::rngs::StdRng`] to replay a run
+    pub fn generate<R: Rng + ?Sized>(rng: &mut R) -> User {
         User {
-            id: User::get_random_string(10), // Default could be rand
-            password: User::get_random_string(13),
+            id: User::get_random_string(rng, 10),
+            password: User::get_random_string(rng, 13),
             email_token: "".to_string(),
             domain: "".to_string(),
         }
     }
+
+    fn get_random_string<R: Rng + ?Sized>(rng: &mut R, len: usize) -> String {
+        (0..len).map(|_| char::from(rng.sample(Alphanumeric))).collect()
+    }
+}
+
+impl Default for User {
+    fn default() -> Self {
+        User::generate(&mut rand::thread_rng())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let user = User::generate(&mut StdRng::seed_from_u64(42));
+        assert_eq!(user, User::generate(&mut StdRng::seed_from_u64(42)));
+        assert_ne!(user, User::generate(&mut StdRng::seed_from_u64(43)));
+        assert_eq!((user.id.len(), user.password.len()), (10, 13));
+        assert!(user.id.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
 }
@@ -0,0 +1,310 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::time::Duration;
+
+use crate::datetime::DateTime;
+use crate::error::Error;
+use crate::http::Client;
+use crate::messages;
+use crate::hydra::HydraCollection;
+use crate::messages::{Message, MessageSummary};
+
+/// A filter on sender, subject and body used to wait for a message
+///
+/// Every condition that is set has to match, string conditions are case insensitive substrings.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::wait::MessageFilter;
+///
+/// let filter = MessageFilter::new()
+///     .from("noreply@example.com")
+///     .subject_contains("verify")
+///     .matching(|message| message.has_attachments);
+/// ```
+#[derive(Default, Clone)]
+pub struct MessageFilter {
+    from: Option<String>,
+    subject: Option<String>,
+    body: Option<String>,
+    received_after: Option<DateTime>,
+    predicate: Option<Predicate>,
+}
+
+type Predicate = Arc<dyn Fn(&Message) -> bool + Send + Sync>;
+
+impl fmt::Debug for MessageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageFilter")
+            .field("from", &self.from)
+            .field("subject", &self.subject)
+            .field("body", &self.body)
+            .field("received_after", &self.received_after)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+impl MessageFilter {
+    /// A filter that matches any message
+    pub fn new() -> MessageFilter {
+        MessageFilter::default()
+    }
+
+    pub fn from(self, address: &str) -> MessageFilter {
+        MessageFilter {
+            from: Some(address.to_lowercase()),
+            ..self
+        }
+    }
+
+    pub fn subject_contains(self, subject: &str) -> MessageFilter {
+        MessageFilter {
+            subject: Some(subject.to_lowercase()),
+            ..self
+        }
+    }
+
+    /// Matches against both the text and html bodies
+    pub fn body_contains(self, body: &str) -> MessageFilter {
+        MessageFilter {
+            body: Some(body.to_lowercase()),
+            ..self
+        }
+    }
+
+    /// Only matches messages received after `after`, ie the time an action was triggered
+    pub fn received_after(self, after: DateTime) -> MessageFilter {
+        MessageFilter {
+            received_after: Some(after),
+            ..self
+        }
+    }
+
+    /// Adds a custom predicate on top of the other conditions
+    pub fn matching<F>(self, predicate: F) -> MessageFilter
+        where F: Fn(&Message) -> bool + Send + Sync + 'static {
+        MessageFilter {
+            predicate: Some(Arc::new(predicate)),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        contains(&message.from.address, &self.from)
+            && contains(&message.subject, &self.subject)
+            && (contains(&message.text, &self.body)
+            || message.html.iter().any(|html| contains(html, &self.body)))
+            && self.received_after.as_ref().map(|after| message.received_after(after)).unwrap_or(true)
+            && self.predicate.as_ref().map(|predicate| predicate(message)).unwrap_or(true)
+    }
+
+    /// Whether a message could match from its summary alone, so only those are fetched in full
+    /// for the body and the custom predicate
+    fn may_match(&self, summary: &MessageSummary) -> bool {
+        contains(&summary.from.address, &self.from)
+            && contains(&summary.subject, &self.subject)
+            && self.received_after.as_ref().map(|after| summary.received_after(after)).unwrap_or(true)
+    }
+}
+
+fn contains(haystack: &str, needle: &Option<String>) -> bool {
+    match needle {
+        Some(needle) => haystack.to_lowercase().contains(needle),
+        None => true,
+    }
+}
+
+/// Polls the inbox until a message matching `filter` arrives or `timeout` elapses
+///
+/// Only new messages whose summary matches the sender and subject are fetched in full, so each poll
+/// costs a single request when nothing relevant has arrived.
+pub async fn wait_for_message(client: &Client, token: &str, filter: &MessageFilter, timeout: Duration, poll_interval: Duration) -> Result<Message, Error> {
+    poll(
+        || messages::messages(client, token, None),
+        |id| async move { messages::get(client, token, &id).await },
+        filter,
+        timeout,
+        poll_interval,
+    ).await
+}
+
+/// The polling loop behind [`wait_for_message`], generic over how the inbox is read
+///
+/// `timeout` bounds the whole wait including the requests in flight, which may each take several
+/// attempts.
+pub(crate) async fn poll<L, LF, G, GF>(mut list: L, mut get: G, filter: &MessageFilter, timeout: Duration, poll_interval: Duration) -> Result<Message, Error>
+    where L: FnMut() -> LF,
+          LF: Future<Output=Result<HydraCollection<MessageSummary>, Error>>,
+          G: FnMut(String) -> GF,
+          GF: Future<Output=Result<Message, Error>> {
+    let mut seen = HashSet::new();
+
+    log::debug!("Waiting up to {:?} for a message matching {:?}", timeout, filter);
+
+    let polling = async {
+        loop {
+            let page = list().await?;
+            for summary in page.members {
+                if !seen.insert(summary.id2.clone()) || !filter.may_match(&summary) {
+                    continue;
+                }
+                let message = match get(summary.id2).await {
+                    Ok(message) => message,
+                    // Deleted since it was listed
+                    Err(Error::NotFound(res)) => {
+                        log::trace!("Skipping a message that's gone: {}", res);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if filter.matches(&message) {
+                    log::trace!("Found a matching message {}", message.id2);
+                    return Ok(message);
+                }
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    };
+
+    match tokio::time::timeout(timeout, polling).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::WaitTimeout(timeout)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
+
+    use crate::messages::From;
+
+    use super::*;
+
+    fn message(id: &str, subject: &str) -> Message {
+        Message {
+            from: From {
+                address: "NoReply@Example.com".to_string(),
+                name: "".to_string(),
+            },
+            subject: subject.to_string(),
+            text: "Your code is 1234".to_string(),
+            ..crate::messages::message(id)
+        }
+    }
+
+    fn collection(messages: &[Message]) -> serde_json::Value {
+        serde_json::json!({
+            "hydra:member": messages,
+            "hydra:totalItems": messages.len()
+        })
+    }
+
+    #[test]
+    fn test_filter() {
+        let message = message("1", "Please verify your email");
+        assert!(MessageFilter::new().matches(&message));
+        assert!(MessageFilter::new().from("noreply@example.com").subject_contains("VERIFY").matches(&message));
+        assert!(MessageFilter::new().body_contains("code is").matches(&message));
+        assert!(!MessageFilter::new().body_contains("password").matches(&message));
+        assert!(!MessageFilter::new().matching(|message| message.has_attachments).matches(&message));
+
+        let parse = |text| crate::datetime::parse(text).unwrap();
+        let received_after = MessageFilter::new().received_after(parse("2021-06-01T00:00:00+00:00"));
+        assert!(!received_after.matches(&message));
+        let message = Message {
+            created_at: Some(parse("2021-06-02T00:00:00+00:00")),
+            ..message
+        };
+        assert!(received_after.matches(&message));
+        assert!(!MessageFilter::new().received_after(parse("2021-06-03T00:00:00+00:00")).matches(&message));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_message() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        let welcome = message("1", "Welcome");
+        let verify = message("2", "Verify your email");
+        let gone = message("3", "Verify your email");
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(collection(&[])))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(collection(std::slice::from_ref(&welcome))))
+            .up_to_n_times(1)
+            .with_priority(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(collection(&[gone, verify.clone(), welcome.clone()])))
+            .mount(&server)
+            .await;
+        // ruled out by its subject without being fetched
+        Mock::given(method("GET"))
+            .and(path("/messages/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&welcome))
+            .expect(0)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/messages/2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&verify))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // deleted between listing and fetching
+        Mock::given(method("GET"))
+            .and(path("/messages/3"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+        let filter = MessageFilter::new().subject_contains("verify");
+        let message = wait_for_message(&client, "jwt", &filter, Duration::from_secs(5), Duration::from_millis(10)).await?;
+        assert_eq!(message.id2, "2");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_message_timeout() -> Result<(), Error> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(collection(&[])))
+            .mount(&server)
+            .await;
+
+        let client = Client::new()?.with_base_url(&server.uri());
+        let timeout = wait_for_message(&client, "jwt", &MessageFilter::new(), Duration::from_millis(50), Duration::from_millis(10)).await;
+        assert!(matches!(timeout, Err(Error::WaitTimeout(_))));
+
+        // a request in flight doesn't outlive the timeout
+        let slow = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(collection(&[])).set_delay(Duration::from_secs(5)))
+            .mount(&slow)
+            .await;
+
+        let client = Client::new()?.with_base_url(&slow.uri());
+        let start = tokio::time::Instant::now();
+        let timeout = wait_for_message(&client, "jwt", &MessageFilter::new(), Duration::from_millis(100), Duration::from_millis(10)).await;
+        assert!(matches!(timeout, Err(Error::WaitTimeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+}